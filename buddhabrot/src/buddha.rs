@@ -1,15 +1,21 @@
 
 extern crate rand;
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc::channel;
-use std::ops::{Add, Mul};
+use std::sync::Arc;
+use std::ops::{Add, Mul, Sub};
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::f64::consts;
 use std::cmp::{min, max};
 use std::time::Duration;
 use std::io::Write;
 use std::fs::File;
+use std::path::PathBuf;
 use std::thread;
-use rand::Rng;
+use rand::{ChaChaRng, Rng, SeedableRng};
 use std::fmt;
 use std::io;
 use std;
@@ -17,13 +23,16 @@ use std;
 extern crate serde;
 extern crate serde_json;
 
+use interior::{InteriorMask, Masked};
 use ppm;
+use viewport::{self, Viewport};
+use watchdog::Watchdog;
 
 /// An implementation of Complex numbers. I could use the `num` crate which has an existing generic
 /// implementation of Complex, and in fact that is what I used to use. However, I couldn't get it
 /// to work with Serde, so I wrote my own implementation with concrete floats that works with
 /// Serde out of the box.
-#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Default, PartialEq)]
 pub struct Complex {
     pub re: f64,
     pub im: f64,
@@ -50,6 +59,16 @@ impl Add<Complex> for Complex {
     }
 }
 
+impl Sub<Complex> for Complex {
+    type Output = Complex;
+    fn sub(self, _rhs: Complex) -> Complex {
+        Complex {
+            re: self.re - _rhs.re,
+            im: self.im - _rhs.im,
+        }
+    }
+}
+
 impl fmt::Display for Complex {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "({:5.1}+{:5.1}j)", self.re, self.im)
@@ -58,11 +77,94 @@ impl fmt::Display for Complex {
 
 impl Complex {
     pub fn new(re: f64, im: f64) -> Complex {
-        return Complex { re: re, im: im };
+        Complex { re, im }
     }
     pub fn norm(&self) -> f64 {
         self.re.hypot(self.im)
     }
+    /// Returns the Mandelbrot orbit of this number as `c`.
+    pub fn orbit(self) -> Orbit {
+        Orbit::new(self)
+    }
+    pub fn conj(self) -> Complex {
+        Complex::new(self.re, -self.im)
+    }
+    /// Raises this number to the power `d`, which must be positive. Whole powers are found by
+    /// repeated multiplication, which is both faster and more precise than going through polar
+    /// form; other powers use the principal branch.
+    pub fn pow(self, d: f64) -> Complex {
+        if d == 2.0 {
+            return self * self;
+        }
+        if d.fract() == 0.0 && d > 0.0 && d <= u32::MAX as f64 {
+            let mut n = d as u32;
+            let mut base = self;
+            let mut result = Complex::new(1.0, 0.0);
+            while n > 0 {
+                if n & 1 == 1 {
+                    result = result * base;
+                }
+                base = base * base;
+                n >>= 1;
+            }
+            return result;
+        }
+        if self.re == 0.0 && self.im == 0.0 {
+            return self;
+        }
+        let r = self.norm().powf(d);
+        let theta = self.im.atan2(self.re) * d;
+        Complex::new(r * theta.cos(), r * theta.sin())
+    }
+}
+
+/// The exponent `d` of the classic Mandelbrot set. Every other exponent iterates `z = z^d + c`,
+/// making a Multibrot.
+pub const MANDELBROT_EXPONENT: f64 = 2.0;
+
+/// The maps which may be iterated to find orbits. Each is raised to the exponent `d` of the
+/// trajectory being iterated, which is 2 for the usual forms written below.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Formula {
+    /// `z^2 + c`
+    #[default]
+    Mandelbrot,
+    /// `(|re(z)| + i|im(z)|)^2 + c`
+    BurningShip,
+    /// `conj(z)^2 + c`, also known as the Mandelbar.
+    Tricorn,
+    /// `|re(z^2)| + i im(z^2) + c`
+    Celtic,
+}
+
+impl Formula {
+    /// Performs one iteration of this formula on `z` with the exponent `d`.
+    #[inline]
+    pub fn step(self, z: Complex, c: Complex, d: f64) -> Complex {
+        match self {
+            Formula::Mandelbrot => z.pow(d) + c,
+            Formula::BurningShip => Complex::new(z.re.abs(), z.im.abs()).pow(d) + c,
+            Formula::Tricorn => z.conj().pow(d) + c,
+            Formula::Celtic => {
+                let w = z.pow(d);
+                Complex::new(w.re.abs(), w.im) + c
+            }
+        }
+    }
+}
+
+impl FromStr for Formula {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Formula, String> {
+        match s {
+            "mandelbrot" | "multibrot" => Ok(Formula::Mandelbrot),
+            "burning-ship" | "burningship" => Ok(Formula::BurningShip),
+            "tricorn" | "mandelbar" => Ok(Formula::Tricorn),
+            "celtic" => Ok(Formula::Celtic),
+            _ => Err(format!("unknown formula '{}'", s)),
+        }
+    }
 }
 
 
@@ -77,6 +179,10 @@ pub struct Waypoint {
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Trajectory {
     pub init_c: Complex,
+    /// The first `z` of the orbit. This is 0 unless the trajectory belongs to a Julia set, in
+    /// which case `init_c` is the Julia set's constant and `init_z` is what was sampled.
+    #[serde(default)]
+    pub init_z: Complex,
     // We won't serialize the "waypoints" field when creating a JSON string, since that would take
     // up way too much space for long trajectories. However, the 'length' field will be serialized
     // which for my purposes is all that'll be needed.
@@ -84,6 +190,84 @@ pub struct Trajectory {
     pub waypoints: Vec<Waypoint>,
     /// Length is the number of valid waypoints within the
     pub length: i64,
+    /// The importance weight of this trajectory relative to the other trajectories produced by
+    /// the same run. Uniformly sampled trajectories always have a weight of 1.0, while samplers
+    /// which favor some regions of the complex plane (such as Metropolis-Hastings) emit the
+    /// inverse of how strongly they favored this trajectory, so that renderers which scale each
+    /// trajectory by its weight remain unbiased.
+    #[serde(default = "default_weight")]
+    pub weight: f64,
+    /// The number of points of this trajectory which landed inside the viewport it was searched
+    /// for, if it was searched for with a particular viewport in mind.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub view_hits: Option<i64>,
+    /// Whether this trajectory escaped or stayed bounded. Trajectories written before bounded
+    /// orbits were collected are all escaping.
+    #[serde(default)]
+    pub kind: OrbitKind,
+    /// The period of the cycle a bounded trajectory settles into, if one was detected.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub period: Option<i64>,
+    /// The formula this trajectory was found by iterating.
+    #[serde(default)]
+    pub formula: Formula,
+    /// The exponent `d` this trajectory was found by iterating `z = z^d + c` with.
+    #[serde(default = "default_exponent")]
+    pub exponent: f64,
+}
+
+/// The two populations of orbits. Escaping orbits are the ones a Buddhabrot is made of, while
+/// bounded orbits never escape and make up an anti-Buddhabrot. A bounded trajectory's length is
+/// the number of iterations it should be plotted for, so the points its orbit settles onto build
+/// up the longer it is.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum OrbitKind {
+    #[default]
+    Escaping,
+    Bounded,
+}
+
+/// A TrajectoryHeader may be written as the first line of a stream of trajectories, telling
+/// readers the range of lengths the trajectories which follow fall within without them having to
+/// look at every trajectory first.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TrajectoryHeader {
+    pub min_length: i64,
+    pub max_length: i64,
+}
+
+fn default_weight() -> f64 {
+    1.0
+}
+
+fn default_exponent() -> f64 {
+    MANDELBROT_EXPONENT
+}
+
+impl Trajectory {
+    pub fn new(init_c: Complex) -> Trajectory {
+        Trajectory {
+            init_c,
+            init_z: Complex::default(),
+            waypoints: Vec::new(),
+            length: 0,
+            weight: default_weight(),
+            view_hits: None,
+            kind: OrbitKind::Escaping,
+            period: None,
+            formula: Formula::Mandelbrot,
+            exponent: default_exponent(),
+        }
+    }
+
+    /// The built in iteration function this trajectory was found with. Trajectories don't say
+    /// whether they belong to a Julia set, so its escape radius always allows for `init_c` being
+    /// a Julia constant. Replaying only follows an orbit for its length anyway, so an escape
+    /// radius larger than the one it was found with doesn't change it.
+    pub fn function(&self) -> FormulaMap {
+        FormulaMap::new(self.formula, self.exponent).for_julia(self.init_c)
+    }
 }
 
 #[derive(Clone)]
@@ -99,6 +283,25 @@ pub struct Conf {
     pub centery: f64,
     pub zoomlevel: f64,
     pub trajectory_count: usize,
+    /// How long to wait for a trajectory before warning that progress has stalled, or None to
+    /// never warn.
+    pub stall_timeout: Option<Duration>,
+    /// Seeds the random numbers used to pick trajectories, so that a render can be reproduced.
+    pub seed: u64,
+    /// Which kind of orbit to render. Bounded orbits render an anti-Buddhabrot.
+    pub orbits: OrbitKind,
+    /// The formula to iterate.
+    pub formula: Formula,
+    /// The exponent `d` to iterate `z = z^d + c` with.
+    pub exponent: f64,
+    /// When set, renders the Julia set with this constant `c`, sampling the starting `z` of each
+    /// orbit instead of `c`.
+    pub julia: Option<Complex>,
+    /// A mask of points known to be inside the set, which are skipped without iterating them.
+    pub interior_mask: Option<Arc<InteriorMask>>,
+    /// Where to log how many trajectories lasted each number of iterations, or None to skip the
+    /// log.
+    pub itercounts_file: Option<PathBuf>,
 }
 
 impl fmt::Display for Conf {
@@ -116,7 +319,15 @@ impl fmt::Display for Conf {
     centerx: {},
     centery: {},
     zoomlevel: {},
-    trajectory_count: {}
+    trajectory_count: {},
+    stall_timeout: {:?},
+    seed: {},
+    orbits: {:?},
+    formula: {:?},
+    exponent: {},
+    julia: {:?},
+    interior_mask: {},
+    itercounts_file: {:?}
 }}",
             self.json_file,
             self.thread_count,
@@ -128,14 +339,52 @@ impl fmt::Display for Conf {
             self.centerx,
             self.centery,
             self.zoomlevel,
-            self.trajectory_count
+            self.trajectory_count,
+            self.stall_timeout,
+            self.seed,
+            self.orbits,
+            self.formula,
+            self.exponent,
+            self.julia,
+            match self.interior_mask {
+                Some(ref mask) => format!("{}x{}", mask.width(), mask.height()),
+                None => "none".to_string(),
+            },
+            self.itercounts_file
         )
     }
 }
 
-// tells us if a point in the complex plane will loop forever by telling us if it's within the main
-// cardiod or within the second-order bulb.
-fn will_loop_forever(z: Complex) -> bool {
+/// Returns the random number generator for one unit of work. Work is split into numbered units
+/// which each get their own stream of random numbers derived from the run's seed, so the same seed
+/// always produces the same results no matter how many threads the units are spread across.
+/// ChaCha is used since its output is the same on every platform.
+pub fn unit_rng(seed: u64, unit: u64) -> ChaChaRng {
+    ChaChaRng::from_seed(&[
+        seed as u32,
+        (seed >> 32) as u32,
+        unit as u32,
+        (unit >> 32) as u32,
+    ])
+}
+
+/// Tells us if a point in the complex plane will loop forever when iterated by `formula` with the
+/// exponent `d`. For the Mandelbrot set that's when it's within the main cardiod, the
+/// second-order bulb, or one of the disks from `bulb_disks`. For other whole exponents it's when
+/// it's within the disk around 0 which fits inside the main component, and for fractional
+/// exponents or formulas other than the Mandelbrot no shortcut is taken.
+///
+/// This returning false doesn't guarantee that there's a finite number of loops, as this is just
+/// a quick special case to speed things up.
+pub fn will_loop_forever(z: Complex, formula: Formula, d: f64) -> bool {
+    if formula != Formula::Mandelbrot {
+        return false;
+    }
+    if d != MANDELBROT_EXPONENT {
+        // The main component is where z^d + c has an attracting fixed point. The nearest its
+        // boundary comes to 0 is (d - 1) * d^(-d / (d - 1)), which is 1/4 when d is 2.
+        return d.fract() == 0.0 && d > 1.0 && z.norm() < (d - 1.0) * d.powf(-d / (d - 1.0));
+    }
     let x = z.re;
     let y = z.im;
     let p: f64 = ((x - 0.25).powi(2) + y.powi(2)).sqrt();
@@ -145,104 +394,520 @@ fn will_loop_forever(z: Complex) -> bool {
     if ((x + 1.0).powi(2) + y.powi(2)) < 0.0625 {
         return true;
     }
-    return false;
+    bulb_disks().iter().any(|&(center, radius)| {
+        (x - center.re).powi(2) + (y - center.im).powi(2) < radius * radius
+    })
+}
+
+/// Disks which fit inside more of the bulbs of the Mandelbrot set: the p/q bulbs attached to the
+/// main cardioid for q up to 10, the period-4 bulb attached to the period-2 bulb, and the main
+/// cardioid of the period-3 minibrot on the real axis. The p/q bulbs are close to disks of
+/// radius sin(pi p/q) / q^2 touching the cardioid at their root, so each is tested with a disk
+/// 80% of that size to keep clear of its edge.
+fn bulb_disks() -> &'static [(Complex, f64)] {
+    static DISKS: OnceLock<Vec<(Complex, f64)>> = OnceLock::new();
+    DISKS.get_or_init(|| {
+        let mut disks = vec![
+            (Complex::new(-1.309, 0.0), 0.8 * 0.0588),
+            (Complex::new(-1.7549, 0.0), 0.004),
+        ];
+        for q in 3..=10_u32 {
+            for p in (1..q).filter(|&p| gcd(p, q) == 1) {
+                let t = 2.0 * consts::PI * p as f64 / q as f64;
+                // The root of the bulb on the cardioid, and the outward normal there.
+                let root = Complex::new(
+                    t.cos() / 2.0 - (2.0 * t).cos() / 4.0,
+                    t.sin() / 2.0 - (2.0 * t).sin() / 4.0,
+                );
+                let tangent = Complex::new(
+                    (2.0 * t).sin() / 2.0 - t.sin() / 2.0,
+                    t.cos() / 2.0 - (2.0 * t).cos() / 2.0,
+                );
+                let normal = Complex::new(tangent.im, -tangent.re);
+                let radius = (consts::PI * p as f64 / q as f64).sin() / (q * q) as f64;
+                let center = Complex::new(
+                    root.re + radius * normal.re / normal.norm(),
+                    root.im + radius * normal.im / normal.norm(),
+                );
+                disks.push((center, 0.8 * radius));
+            }
+        }
+        disks
+    })
+}
+
+fn gcd(a: u32, b: u32) -> u32 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+/// An IterationFunction is a map whose orbits make up a Buddhabrot. Each orbit starts from some
+/// `z` and is iterated with `step` until it escapes or runs out of iterations. Implement this to
+/// find and render the orbits of formulas which aren't built in; `Orbit` does the iterating.
+pub trait IterationFunction {
+    /// Returns the point which follows `z` in the orbit belonging to `c`.
+    fn step(&self, z: Complex, c: Complex) -> Complex;
+
+    /// Reports whether the orbit has escaped once it reaches `z`, meaning the rest of the orbit
+    /// heads off to infinity.
+    fn escaped(&self, z: Complex) -> bool {
+        z.norm() > 2.0
+    }
+
+    /// Reports whether the orbit of `c` starting from 0 is known to never escape, without having
+    /// to iterate it. Returning false is always safe; it only makes searching slower.
+    fn known_interior(&self, _c: Complex) -> bool {
+        false
+    }
+
+    /// Estimates how far `c` is from the set, iterating its orbit from 0 at most `iterations`
+    /// times. The estimate is within a small factor of the true distance, so a point is never
+    /// much closer to the set than it says. Returns None when the orbit didn't get far enough
+    /// away within `iterations` to tell, or when the function doesn't know how to estimate it.
+    fn distance_estimate(&self, _c: Complex, _iterations: i64) -> Option<f64> {
+        None
+    }
+}
+
+/// How far from 0 an orbit has to get before its distance estimate is trusted. The estimate is
+/// only exact in the limit, and gets closer the further out the orbit is.
+const DISTANCE_BAILOUT: f64 = 1e6;
+
+/// The built in iteration functions: one of the formulas, raised to an exponent.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(from = "FormulaParams")]
+pub struct FormulaMap {
+    pub formula: Formula,
+    pub exponent: f64,
+    // How far from 0 an orbit has to get before it's sure to escape. This follows from the
+    // exponent and the Julia constant, if any, so it isn't saved.
+    #[serde(skip)]
+    escape_radius: f64,
+}
+
+// What's saved of a FormulaMap.
+#[derive(Deserialize)]
+struct FormulaParams {
+    formula: Formula,
+    exponent: f64,
+}
+
+impl From<FormulaParams> for FormulaMap {
+    fn from(params: FormulaParams) -> FormulaMap {
+        FormulaMap::new(params.formula, params.exponent)
+    }
+}
+
+impl FormulaMap {
+    pub fn new(formula: Formula, exponent: f64) -> FormulaMap {
+        // An orbit further from 0 than both 2^(1/(d - 1)) and c gets further away with every
+        // step, since |z^d| - |c| > 2|z| - |z| then. Orbits starting from 0 only need to get
+        // beyond 2^(1/(d - 1)), as every c beyond it escapes as well. That's within 2 for
+        // exponents of 2 and up, which keep the usual radius of 2.
+        let escape_radius = if exponent < 2.0 {
+            2.0_f64.powf(1.0 / (exponent - 1.0))
+        } else {
+            2.0
+        };
+        FormulaMap {
+            formula,
+            exponent,
+            escape_radius,
+        }
+    }
+
+    /// The same map for iterating the Julia set of `c`. Orbits which don't start from 0 can be
+    /// closer to 0 than `c` and still be bounded, so they're only sure to escape once they're
+    /// further from 0 than `c` too.
+    pub fn for_julia(self, c: Complex) -> FormulaMap {
+        FormulaMap {
+            escape_radius: self.escape_radius.max(c.norm()),
+            ..self
+        }
+    }
+}
+
+// Maps are compared to check that saved work was done with the same formula. The escape radius
+// only follows from the exponent and the Julia constant, which is saved separately.
+impl PartialEq for FormulaMap {
+    fn eq(&self, other: &FormulaMap) -> bool {
+        self.formula == other.formula && self.exponent == other.exponent
+    }
+}
+
+impl IterationFunction for FormulaMap {
+    #[inline]
+    fn step(&self, z: Complex, c: Complex) -> Complex {
+        self.formula.step(z, c, self.exponent)
+    }
+
+    fn escaped(&self, z: Complex) -> bool {
+        z.norm() > self.escape_radius
+    }
+
+    fn known_interior(&self, c: Complex) -> bool {
+        will_loop_forever(c, self.formula, self.exponent)
+    }
+
+    // The usual exterior distance estimate, |z| ln |z| / 2|z'|, where z' is the derivative of z
+    // with respect to c. Only the Mandelbrot formula is holomorphic, so the others go without.
+    fn distance_estimate(&self, c: Complex, iterations: i64) -> Option<f64> {
+        if self.formula != Formula::Mandelbrot {
+            return None;
+        }
+        let d = self.exponent;
+        let mut z = Complex::new(0.0, 0.0);
+        let mut dz = Complex::new(0.0, 0.0);
+        for _ in 0..iterations {
+            // z' = d z^(d - 1) z' + 1, where z^(d - 1) is left out while z is 0 so that
+            // fractional exponents don't turn it into NaN.
+            let slope = if d == MANDELBROT_EXPONENT {
+                Complex::new(2.0 * z.re, 2.0 * z.im)
+            } else if z == Complex::default() {
+                Complex::default()
+            } else {
+                Complex::new(d, 0.0) * z.pow(d - 1.0)
+            };
+            dz = slope * dz + Complex::new(1.0, 0.0);
+            z = self.step(z, c);
+            if z.re * z.re + z.im * z.im > DISTANCE_BAILOUT * DISTANCE_BAILOUT {
+                let r = z.norm();
+                return Some(0.5 * r * r.ln() / dz.norm());
+            }
+        }
+        None
+    }
+}
+
+impl<F: IterationFunction + ?Sized> IterationFunction for &F {
+    #[inline]
+    fn step(&self, z: Complex, c: Complex) -> Complex {
+        (**self).step(z, c)
+    }
+
+    fn escaped(&self, z: Complex) -> bool {
+        (**self).escaped(z)
+    }
+
+    fn known_interior(&self, c: Complex) -> bool {
+        (**self).known_interior(c)
+    }
+
+    fn distance_estimate(&self, c: Complex, iterations: i64) -> Option<f64> {
+        (**self).distance_estimate(c, iterations)
+    }
+}
+
+/// How an Orbit came to an end.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OrbitEnd {
+    /// The last point of the orbit escaped.
+    Escaped,
+    /// The last point of the orbit came back to within the cycle epsilon of an earlier point,
+    /// this many iterations after it, so the orbit is caught in a cycle with that period.
+    Cycled(i64),
+    /// The orbit ran out of iterations without doing either of the above.
+    MaxIterations,
+}
+
+/// The distance within which an orbit has to come back to an earlier point to count as cycling,
+/// unless an Orbit is told otherwise.
+pub const DEFAULT_CYCLE_EPSILON: f64 = 1e-12;
+
+/// An Orbit lazily iterates the orbit of `c`, yielding each point after the starting point. By
+/// default it iterates the Mandelbrot map starting from 0, checks for cycles, and goes on for as
+/// long as the orbit does; each of these may be changed before iterating. Once the iterator is
+/// exhausted, `end` tells how the orbit ended. The point the orbit escaped or cycled at is the
+/// last point yielded.
+///
+/// Cycles are found with Brent's algorithm: the orbit keeps a reference point, which is moved up
+/// to the current point each time the number of iterations reaches a power of two, and every
+/// point is compared against it. An orbit caught in a cycle of period `p` is noticed once the
+/// reference point is on the cycle and `p` more iterations have gone by.
+pub struct Orbit<F = FormulaMap> {
+    function: F,
+    c: Complex,
+    z: Complex,
+    max_iterations: i64,
+    check_cycles: bool,
+    cycle_epsilon: f64,
+    iterations: i64,
+    // The reference point and the number of iterations it was reached after.
+    reference: Option<(Complex, i64)>,
+    end: Option<OrbitEnd>,
+}
+
+impl Orbit {
+    pub fn new(c: Complex) -> Orbit {
+        Orbit {
+            function: FormulaMap::new(Formula::Mandelbrot, MANDELBROT_EXPONENT),
+            c,
+            z: Complex::default(),
+            max_iterations: i64::MAX,
+            check_cycles: true,
+            cycle_epsilon: DEFAULT_CYCLE_EPSILON,
+            iterations: 0,
+            reference: None,
+            end: None,
+        }
+    }
+}
+
+impl<F: IterationFunction> Orbit<F> {
+    /// Iterates with `function` instead.
+    pub fn with_function<G: IterationFunction>(self, function: G) -> Orbit<G> {
+        Orbit {
+            function,
+            c: self.c,
+            z: self.z,
+            max_iterations: self.max_iterations,
+            check_cycles: self.check_cycles,
+            cycle_epsilon: self.cycle_epsilon,
+            iterations: self.iterations,
+            reference: self.reference,
+            end: self.end,
+        }
+    }
+
+    /// Starts the orbit from `z0` instead of 0, as for Julia sets.
+    pub fn starting_from(mut self, z0: Complex) -> Orbit<F> {
+        self.z = z0;
+        self
+    }
+
+    /// Stops the orbit after at most `max_iterations` points.
+    pub fn max_iterations(mut self, max_iterations: i64) -> Orbit<F> {
+        self.max_iterations = max_iterations;
+        self
+    }
+
+    /// Turns checking for cycles on or off. Orbits which aren't checked keep going around their
+    /// cycles until they run out of iterations.
+    pub fn check_cycles(mut self, check_cycles: bool) -> Orbit<F> {
+        self.check_cycles = check_cycles;
+        self
+    }
+
+    /// Counts the orbit as cycling once it comes back to within `epsilon` of an earlier point. An
+    /// epsilon of 0 only catches cycles which repeat exactly.
+    pub fn cycle_epsilon(mut self, epsilon: f64) -> Orbit<F> {
+        self.cycle_epsilon = epsilon;
+        self
+    }
+
+    /// The number of points yielded so far.
+    pub fn iterations(&self) -> i64 {
+        self.iterations
+    }
+
+    /// How the orbit ended, or None if it hasn't ended yet.
+    pub fn end(&self) -> Option<OrbitEnd> {
+        self.end
+    }
+
+    /// Reports whether the orbit has ended by escaping.
+    pub fn escaped(&self) -> bool {
+        self.end == Some(OrbitEnd::Escaped)
+    }
+
+    /// The period of the cycle the orbit was caught in, if it ended by cycling.
+    pub fn period(&self) -> Option<i64> {
+        match self.end {
+            Some(OrbitEnd::Cycled(period)) => Some(period),
+            _ => None,
+        }
+    }
+}
+
+impl<F: IterationFunction> Iterator for Orbit<F> {
+    type Item = Complex;
+
+    fn next(&mut self) -> Option<Complex> {
+        if self.end.is_some() {
+            return None;
+        }
+        if self.iterations >= self.max_iterations {
+            self.end = Some(OrbitEnd::MaxIterations);
+            return None;
+        }
+        let itercount = self.iterations;
+        self.z = self.function.step(self.z, self.c);
+        self.iterations += 1;
+        if self.function.escaped(self.z) {
+            self.end = Some(OrbitEnd::Escaped);
+        } else if self.check_cycles {
+            // Check if we've come back around to the reference point (useful for avoiding
+            // cyclical but never ending z's). This algorithm is called "Brent's Algorithm" and
+            // I originally found it here: https://softologyblog.wordpress.com/2011/06/26/buddhabrot-fractals/
+            if let Some((reference, reached_at)) = self.reference {
+                if (self.z - reference).norm() <= self.cycle_epsilon {
+                    self.end = Some(OrbitEnd::Cycled(self.iterations - reached_at));
+                }
+            }
+            // This bit of math is a fancy way of checking if itercount is a power of 2.
+            if self.end.is_none() && itercount & (itercount - 1) == 0 {
+                self.reference = Some((self.z, self.iterations));
+            }
+        }
+        if self.end.is_none() && self.iterations >= self.max_iterations {
+            self.end = Some(OrbitEnd::MaxIterations);
+        }
+        Some(self.z)
+    }
 }
 
+/// Returns the orbit of `trajectory` under `function`, which runs for the length of the
+/// trajectory. Escaping orbits stop once they escape, while bounded orbits are replayed for their
+/// whole length so that the cycles they settle into build up.
+pub fn replay<F: IterationFunction>(function: F, trajectory: &Trajectory) -> Orbit<F> {
+    trajectory
+        .init_c
+        .orbit()
+        .with_function(function)
+        .starting_from(trajectory.init_z)
+        .max_iterations(trajectory.length)
+        .check_cycles(false)
+}
 
+// The number of trajectories found by each unit of work in render_buddhabort.
+const UNIT_SIZE: usize = 16;
+
+/// Renders with the formula and exponent given in `c`.
 pub fn render_buddhabort(c: Conf) -> Vec<ppm::Img> {
-    let startzoom = 2.0;
+    let function = FormulaMap::new(c.formula, c.exponent);
+    let function = match c.julia {
+        Some(julia) => function.for_julia(julia),
+        None => function,
+    };
+    render_buddhabort_with(c, function)
+}
+
+/// Renders by iterating `function`, skipping the points inside the interior mask given in `c`.
+/// The trajectories written out are labelled with the formula and exponent given in `c`, which
+/// should describe `function` so that they can be replayed.
+pub fn render_buddhabort_with<F>(c: Conf, function: F) -> Vec<ppm::Img>
+where
+    F: IterationFunction + Clone + Send + 'static,
+{
+    let function = Masked::new(function, c.interior_mask.clone());
+    // A zoomlevel of 0 frames a region 4 units tall, centered on (centerx, centery), with each
+    // additional zoomlevel halving the size of the frame.
+    let startzoom = 4.0;
+    let view = Viewport::new(
+        Complex::new(c.centerx, c.centery),
+        2.0_f64.powf(c.zoomlevel) * viewport::BASE_HEIGHT / startzoom,
+        c.width as f64 / c.height as f64,
+        0.0,
+    );
     let (startx, stopx) = (
-        c.centerx - (startzoom / (2.0 as f64).powf(c.zoomlevel)),
-        c.centerx + (startzoom / (2.0 as f64).powf(c.zoomlevel)),
+        c.centerx - view.width() / 2.0,
+        c.centerx + view.width() / 2.0,
     );
     let (starty, stopy) = (
-        c.centery - (startzoom / (2.0 as f64).powf(c.zoomlevel)),
-        c.centery + (startzoom / (2.0 as f64).powf(c.zoomlevel)),
+        c.centery - view.height() / 2.0,
+        c.centery + view.height() / 2.0,
     );
     let max_trajectories: usize = c.trajectory_count;
 
     let mut children = vec![];
 
-    let max_thread_traj = max(1, max_trajectories / c.thread_count);
-    let to_recieve: usize = min(c.trajectory_count, max_thread_traj * c.thread_count);
+    // The trajectories are found in units of UNIT_SIZE, which the threads take turns claiming.
+    let unit_count = max_trajectories.div_ceil(UNIT_SIZE);
+    let unit_quota = move |unit: usize| min(UNIT_SIZE, max_trajectories - unit * UNIT_SIZE);
+    let next_unit = Arc::new(AtomicUsize::new(0));
     println!(
-        "Spawning {} threads, each producing {} trajectories, for a total of {} \
-              trajectories being produced",
+        "Spawning {} threads to produce a total of {} trajectories in {} units",
         c.thread_count,
-        max_thread_traj,
-        to_recieve
+        max_trajectories,
+        unit_count
     );
     let (tx, rx) = channel();
     for idx in 0..c.thread_count {
         let child_tx = tx.clone();
         let tconf = c.clone();
-        // Spin up threads to calculate trajectories
+        let tnext_unit = next_unit.clone();
+        let function = function.clone();
+        // Spin up threads to calculate trajectories. Each thread renders the trajectories it
+        // finds into its own shard of the images, which are summed once every thread is done.
         let child = thread::spawn(move || {
             println!("Thread {} started", idx);
-            let mut rng = rand::thread_rng();
-
-            let mut valid_traj = 0;
-
-            while valid_traj < max_thread_traj {
-                let mut escaped = false;
-                let mut z = Complex::new(0.0, 0.0);
-                let cn = Complex::new(
-                    (startx * tconf.samplescale) +
-                        rng.gen::<f64>() *
-                            ((stopx * tconf.samplescale) - (startx * tconf.samplescale)),
-                    (starty * tconf.samplescale) +
-                        rng.gen::<f64>() *
-                            ((stopy * tconf.samplescale) - (starty * tconf.samplescale)),
-                );
-                let mut trajectory: Trajectory = Trajectory {
-                    init_c: cn,
-                    waypoints: Vec::new(),
-                    length: 0,
-                };
-                if will_loop_forever(cn) {
-                    continue;
+            let mut shard = vec![
+                ppm::Img::new(tconf.width, tconf.height),
+                ppm::Img::new(tconf.width, tconf.height),
+                ppm::Img::new(tconf.width, tconf.height),
+            ];
+            // Each unit finds its trajectories using its own stream of random numbers, so which
+            // trajectories get found depends only on the seed, not on the number of threads.
+            'units: loop {
+                let unit = tnext_unit.fetch_add(1, Ordering::Relaxed);
+                if unit >= unit_count {
+                    break;
                 }
-                let mut periods = HashMap::new();
-                for itercount in 0..tconf.max_iterations {
-                    trajectory.length = itercount;
-                    if escaped {
-                        break;
-                    }
-                    z = z * z + cn;
-
-                    // May want to swap x and y for upward facing buddha
-                    let x = (z.re - startx) / (stopx - startx) * tconf.width as f64;
-                    let y = (z.im - starty) / (stopy - starty) * tconf.height as f64;
-
-                    if !(x < 0.0 || x >= (tconf.width as f64) || y < 0.0 ||
-                             y >= (tconf.height as f64))
-                    {
-                        let waypoint = Waypoint {
-                            img_x: x as i32,
-                            img_y: y as i32,
-                            point: z.clone(),
-                        };
-                        trajectory.waypoints.push(waypoint);
+                let mut rng = unit_rng(tconf.seed, unit as u64);
+                let quota = unit_quota(unit);
+                let mut valid_traj = 0;
+
+                while valid_traj < quota {
+                    let anti = tconf.orbits == OrbitKind::Bounded;
+                    let point = Complex::new(
+                        (startx * tconf.samplescale) +
+                            rng.gen::<f64>() *
+                                ((stopx * tconf.samplescale) - (startx * tconf.samplescale)),
+                        (starty * tconf.samplescale) +
+                            rng.gen::<f64>() *
+                                ((stopy * tconf.samplescale) - (starty * tconf.samplescale)),
+                    );
+                    // Julia sets sample the starting z rather than c.
+                    let (z, cn) = match tconf.julia {
+                        Some(c) => (point, c),
+                        None => (Complex::new(0.0, 0.0), point),
+                    };
+                    let mut trajectory: Trajectory = Trajectory::new(cn);
+                    trajectory.init_z = z;
+                    trajectory.formula = tconf.formula;
+                    trajectory.exponent = tconf.exponent;
+                    if !anti && z == Complex::default() && function.known_interior(cn) {
+                        continue;
                     }
-                    if z.norm() > 2.0 {
-                        escaped = true;
+                    // Bounded orbits are wanted for every iteration, so that the cycles they
+                    // settle into build up.
+                    let mut orbit = cn
+                        .orbit()
+                        .with_function(&function)
+                        .starting_from(z)
+                        .max_iterations(tconf.max_iterations)
+                        .check_cycles(!anti);
+                    orbit.by_ref().count();
+                    let escaped = orbit.escaped();
+                    trajectory.length = orbit.iterations();
+                    trajectory.period = orbit.period();
+                    if anti && !escaped {
+                        trajectory.kind = OrbitKind::Bounded;
+                        trajectory.length = tconf.max_iterations;
                     }
-                    // Check if we've encountered this point before (useful for avoiding cyclical
-                    // but never ending z's). This bit of math is a fancy way of checking if
-                    // itercount is a power of 2
-                    if itercount & (itercount - 1) == 0 {
-                        let k = format!("{:?}", z);
-                        if periods.contains_key(&k) {
-                            break;
+                    let wanted = if anti {
+                        !escaped
+                    } else {
+                        escaped && trajectory.length >= tconf.min_iterations
+                    };
+                    if wanted {
+                        // The color depends on how long the orbit lasted, so its points are only
+                        // plotted once it's done, by replaying it.
+                        let channels = channels(&tconf, &trajectory);
+                        for z in replay(&function, &trajectory) {
+                            // May want to swap x and y for upward facing buddha
+                            if let Some((x, y)) = view.to_pixel(z, tconf.width, tconf.height) {
+                                for &channel in channels {
+                                    shard[channel].add_px(x, y, trajectory.weight);
+                                }
+                            }
                         }
-                        periods.insert(k, itercount);
-                    }
-                }
-                if escaped {
-                    if !(trajectory.length < tconf.min_iterations) {
-                        match child_tx.send(trajectory) {
+                        match child_tx.send((unit, trajectory)) {
                             Ok(_) => (),
-                            Err(_) => break,
+                            Err(_) => break 'units,
                         }
                         valid_traj += 1;
                     }
@@ -250,82 +915,352 @@ pub fn render_buddhabort(c: Conf) -> Vec<ppm::Img> {
             }
             println!("Thread {} finished", idx);
             drop(child_tx);
+            shard
         });
         children.push(child);
     }
 
+    println!("Write to json file: {}", c.json_file);
+    let mut json_file = File::create(std::path::Path::new(c.json_file.as_str())).unwrap();
+    let mut iter_freq: HashMap<i64, i64> = HashMap::new();
+
+    println!("Begun recieving trajectories");
+
+    // Each thread hangs up its end of the channel once it has produced its share of the
+    // trajectories, so receiving ends once every thread is done. The watchdog only complains on
+    // stderr when no trajectory shows up for a while; it doesn't stop anything.
+    drop(tx);
+    let watchdog = Watchdog::start("trajectories received", c.stall_timeout);
+    let ticker = watchdog.ticker();
+
+    // Trajectories arrive in whatever order the threads find them, but they're written out in
+    // unit order once each unit is complete, so the json file only depends on the seed and not
+    // on how many threads there are.
+    let mut unwritten: BTreeMap<usize, Vec<String>> = BTreeMap::new();
+    let mut next_to_write = 0;
+
+    // Receive each trajectory found by the workers, writing it out and counting its length.
+    for (traj, (unit, trajectory)) in rx.iter().enumerate() {
+        ticker.tick();
+        if (traj % max(max_trajectories / 100, 1)) == 0 {
+            print!(
+                "{}%\r",
+                ((traj as f64 / max_trajectories as f64) * 100.0) as u32
+            );
+            io::stdout().flush().unwrap();
+        }
+        unwritten
+            .entry(unit)
+            .or_default()
+            .push(serde_json::to_string(&trajectory).unwrap());
+        while unwritten
+            .get(&next_to_write)
+            .is_some_and(|lines| lines.len() == unit_quota(next_to_write))
+        {
+            for line in unwritten.remove(&next_to_write).unwrap() {
+                writeln!(json_file, "{}", line).unwrap();
+            }
+            next_to_write += 1;
+        }
+        let freq = iter_freq.entry(trajectory.length).or_insert(0);
+        *freq += 1;
+    }
+    drop(watchdog);
     // Our vector of images, each representing a color channel, in order [r, g, b].
     let mut imgs: Vec<ppm::Img> = vec![
         ppm::Img::new(c.width, c.height),
         ppm::Img::new(c.width, c.height),
         ppm::Img::new(c.width, c.height),
     ];
+    for child in children {
+        let shard = child.join().expect("a trajectory thread panicked");
+        for (img, shard_img) in imgs.iter_mut().zip(shard.iter()) {
+            img.add(shard_img);
+        }
+    }
+    if let Some(ref path) = c.itercounts_file {
+        let mut logfile = File::create(path).unwrap();
+        for (key, val) in iter_freq.iter() {
+            writeln!(logfile, "{} {}", key, val).unwrap();
+        }
+    }
+    println!("Finished coming up with pixel values");
 
-    println!("Write to json file: {}", c.json_file);
-    let mut json_file = File::create(&std::path::Path::new(c.json_file.as_str())).unwrap();
-    let mut logfile = File::create("itercounts.txt").unwrap();
-    let mut iter_freq: HashMap<i64, i64> = HashMap::new();
+    imgs
+}
 
-    println!("Begun recieving trajectories");
+// The color channels the points of `trajectory` are plotted in.
+fn channels(c: &Conf, trajectory: &Trajectory) -> &'static [usize] {
+    let iter_span: f64 = (c.max_iterations - c.min_iterations) as f64;
+    let min_iters: f64 = c.min_iterations as f64;
 
-    // If the program is failing to find *anything* for long enough, we want it to time out and
-    // just print what we've got. The timeout is thus based on a bare minimum, 1/4 of a second,
-    // plus 100 * the minimum number of iterations. It's a pretty usable heuristic.
-    let timeout = Duration::from_millis(250 + (100 * c.min_iterations) as u64);
-
-    // Receive each trajectory found by the workers, using the waypoints of that trajectory to
-    // increment brightness values of the output images.
-    for traj in 0..to_recieve {
-        match rx.recv_timeout(timeout) {
-            Ok(trajectory) => {
-                if (traj % max(max_trajectories / 100, 1)) == 0 {
-                    print!(
-                        "{}%\r",
-                        ((traj as f64 / max_trajectories as f64) * 100.0) as u32
-                    );
-                    io::stdout().flush().unwrap();
-                }
-                write!(
-                    json_file,
-                    "{}\n",
-                    serde_json::to_string(&trajectory).unwrap()
-                ).unwrap();
-                let final_iteration = trajectory.length;
-                let freq = iter_freq.entry(final_iteration).or_insert(0);
-                *freq += 1;
-                for p in trajectory.waypoints {
-                    let iter_span: f64 = (c.max_iterations - c.min_iterations) as f64;
-                    let min_iters: f64 = c.min_iterations as f64;
-
-                    // If we've set a sufficiently high minimum iteration number, then the
-                    // distribution of discovered orbits will be much more uniform, so make the
-                    // color distribution uniform. Otherwise, have it be inverse log base 10 to
-                    // compensate for the large number of small orbits.
-                    let red_factor = if c.min_iterations > 100 { 0.40 } else { 0.10 };
-                    let green_factor = if c.min_iterations > 100 { 0.10 } else { 0.01 };
-
-                    let red_min = ((iter_span * red_factor) + min_iters) as i64;
-                    let green_min = ((iter_span * green_factor) + min_iters) as i64;
-                    let blue_max = green_min;
-                    if final_iteration > red_min {
-                        imgs[0].incr_px(p.img_x as i64, p.img_y as i64);
-                    } else if final_iteration > green_min {
-                        imgs[1].incr_px(p.img_x as i64, p.img_y as i64);
-                    } else if final_iteration < blue_max {
-                        imgs[2].incr_px(p.img_x as i64, p.img_y as i64);
-                    }
+    // If we've set a sufficiently high minimum iteration number, then the distribution of
+    // discovered orbits will be much more uniform, so make the color distribution uniform.
+    // Otherwise, have it be inverse log base 10 to compensate for the large number of small
+    // orbits.
+    let red_factor = if c.min_iterations > 100 { 0.40 } else { 0.10 };
+    let green_factor = if c.min_iterations > 100 { 0.10 } else { 0.01 };
+
+    let red_min = ((iter_span * red_factor) + min_iters) as i64;
+    let green_min = ((iter_span * green_factor) + min_iters) as i64;
+    let blue_max = green_min;
+    let final_iteration = trajectory.length;
+    if trajectory.kind == OrbitKind::Bounded {
+        // Bounded orbits all have the same length, so they're all plotted in one color.
+        &[1, 2]
+    } else if final_iteration > red_min {
+        &[0]
+    } else if final_iteration > green_min {
+        &[1]
+    } else if final_iteration < blue_max {
+        &[2]
+    } else {
+        &[]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn orbit_reports_escapes() {
+        let mut orbit = Complex::new(1.0, 0.0).orbit();
+        let points: Vec<Complex> = orbit.by_ref().collect();
+        // 0 -> 1 -> 2 -> 5
+        assert_eq!(points.len(), 3);
+        assert_eq!(points[2], Complex::new(5.0, 0.0));
+        assert_eq!(orbit.end(), Some(OrbitEnd::Escaped));
+        assert_eq!(orbit.iterations(), 3);
+    }
+
+    #[test]
+    fn orbit_reports_cycles() {
+        // 0 -> -1 -> 0 -> -1 -> ...
+        let mut orbit = Complex::new(-1.0, 0.0).orbit();
+        assert!(orbit.by_ref().count() < 10);
+        assert_eq!(orbit.end(), Some(OrbitEnd::Cycled(2)));
+
+        let mut unchecked = Complex::new(-1.0, 0.0)
+            .orbit()
+            .check_cycles(false)
+            .max_iterations(10);
+        assert_eq!(unchecked.by_ref().count(), 10);
+        assert_eq!(unchecked.end(), Some(OrbitEnd::MaxIterations));
+    }
+
+    #[test]
+    fn orbit_detects_attracting_cycles_within_epsilon() {
+        // Inside the period 3 bulb the orbit only approaches its cycle, so it never repeats
+        // exactly.
+        let c = Complex::new(-0.1226, 0.7449);
+        let mut orbit = c.orbit().max_iterations(100_000);
+        orbit.by_ref().count();
+        assert_eq!(orbit.period(), Some(3));
+        assert!(orbit.iterations() < 10_000);
+
+        let mut loose = c.orbit().cycle_epsilon(1e-3).max_iterations(100_000);
+        loose.by_ref().count();
+        assert_eq!(loose.period(), Some(3));
+        assert!(loose.iterations() < orbit.iterations());
+    }
+
+    #[test]
+    fn formulas_take_the_right_step() {
+        let c = Complex::new(0.5, 0.25);
+        // z^2 for (3, 1) is (8, 6), for (-3, 1) it's (8, -6), and for (1, -2) it's (-3, -4).
+        let cases = [
+            (Formula::Mandelbrot, 2.0, (3.0, 1.0), (8.5, 6.25)),
+            (Formula::Mandelbrot, 2.0, (-3.0, 1.0), (8.5, -5.75)),
+            (Formula::Mandelbrot, 2.0, (1.0, -2.0), (-2.5, -3.75)),
+            (Formula::Mandelbrot, 3.0, (1.0, 1.0), (-1.5, 2.25)),
+            // The burning ship squares (|x|, |y|), which only matters when x and y differ in sign.
+            (Formula::BurningShip, 2.0, (3.0, 1.0), (8.5, 6.25)),
+            (Formula::BurningShip, 2.0, (-3.0, 1.0), (8.5, 6.25)),
+            (Formula::BurningShip, 2.0, (1.0, -2.0), (-2.5, 4.25)),
+            // The tricorn squares the conjugate, flipping the sign of the imaginary part.
+            (Formula::Tricorn, 2.0, (3.0, 1.0), (8.5, -5.75)),
+            (Formula::Tricorn, 2.0, (-3.0, 1.0), (8.5, 6.25)),
+            (Formula::Tricorn, 2.0, (1.0, -2.0), (-2.5, 4.25)),
+            (Formula::Tricorn, 3.0, (1.0, 1.0), (-1.5, -1.75)),
+            // The celtic takes the absolute value of the real part of z^2.
+            (Formula::Celtic, 2.0, (3.0, 1.0), (8.5, 6.25)),
+            (Formula::Celtic, 2.0, (-3.0, 1.0), (8.5, -5.75)),
+            (Formula::Celtic, 2.0, (1.0, -2.0), (3.5, -3.75)),
+        ];
+        for &(formula, d, (x, y), (re, im)) in cases.iter() {
+            let z = Complex::new(x, y);
+            assert_eq!(
+                formula.step(z, c, d),
+                Complex::new(re, im),
+                "{:?} with d = {} from {}",
+                formula,
+                d,
+                z
+            );
+        }
+    }
+
+    #[test]
+    fn known_interior_points_never_escape() {
+        let mut known = 0;
+        for y in 0..60 {
+            for x in 0..100 {
+                let c = Complex::new(-2.0 + 2.5 * x as f64 / 100.0, -1.2 + 2.4 * y as f64 / 60.0);
+                if will_loop_forever(c, Formula::Mandelbrot, MANDELBROT_EXPONENT) {
+                    known += 1;
+                    let mut orbit = c.orbit().check_cycles(false).max_iterations(5000);
+                    orbit.by_ref().count();
+                    assert!(!orbit.escaped(), "{} escaped", c);
                 }
             }
-            Err(_) => {
-                println!("\n\nTimed out!\n\n");
-                break;
+        }
+        assert!(known > 1000);
+        // Deep inside the period 3 bulbs and the period 4 bulb.
+        for &c in &[
+            Complex::new(-0.1226, 0.7449),
+            Complex::new(-0.1226, -0.7449),
+            Complex::new(-1.3107, 0.0),
+        ] {
+            assert!(will_loop_forever(c, Formula::Mandelbrot, MANDELBROT_EXPONENT));
+        }
+    }
+
+    #[test]
+    fn bulb_disks_lie_inside_the_set() {
+        // Points on the rim of each disk are as close to the edge of its bulb as the disk gets.
+        for &(center, radius) in bulb_disks() {
+            for k in 0..64 {
+                let angle = 2.0 * consts::PI * k as f64 / 64.0;
+                let c = Complex::new(
+                    center.re + radius * angle.cos(),
+                    center.im + radius * angle.sin(),
+                );
+                let mut orbit = c.orbit().max_iterations(20_000);
+                orbit.by_ref().count();
+                assert!(!orbit.escaped(), "{} escaped after {}", c, orbit.iterations());
             }
         }
     }
-    for (key, val) in iter_freq.iter() {
-        write!(logfile, "{} {}\n", key, val).unwrap();
+
+    #[test]
+    fn distance_estimates_are_close_to_the_distance() {
+        let function = FormulaMap::new(Formula::Mandelbrot, MANDELBROT_EXPONENT);
+        // The nearest point of the set to 1 is the cusp at 1/4, and to -2.5 it's the tip at -2.
+        for &(c, distance) in &[(Complex::new(1.0, 0.0), 0.75), (Complex::new(-2.5, 0.0), 0.5)] {
+            let estimate = function.distance_estimate(c, 64).unwrap();
+            assert!(
+                estimate <= distance && estimate > distance / 4.0,
+                "{} estimated {}",
+                c,
+                estimate
+            );
+        }
+        let near = function.distance_estimate(Complex::new(0.2501, 0.0), 1000).unwrap();
+        assert!(near < 1e-4, "{}", near);
+        // Orbits which haven't escaped yet, and formulas without an estimate.
+        assert_eq!(function.distance_estimate(Complex::new(0.2501, 0.0), 64), None);
+        assert_eq!(function.distance_estimate(Complex::new(-0.1, 0.0), 1000), None);
+        let ship = FormulaMap::new(Formula::BurningShip, MANDELBROT_EXPONENT);
+        assert_eq!(ship.distance_estimate(Complex::new(1.0, 0.0), 64), None);
+        let cubic = FormulaMap::new(Formula::Mandelbrot, 3.0);
+        assert!(cubic.distance_estimate(Complex::new(1.0, 1.0), 64).is_some());
+    }
+
+    #[test]
+    fn orbit_follows_its_function_and_start() {
+        let julia = Complex::new(0.0, 0.0)
+            .orbit()
+            .starting_from(Complex::new(3.0, 0.0))
+            .max_iterations(5);
+        assert_eq!(julia.collect::<Vec<_>>(), vec![Complex::new(9.0, 0.0)]);
+
+        let cubic = FormulaMap::new(Formula::Mandelbrot, 3.0);
+        let mut orbit = Complex::new(1.0, 0.0).orbit().with_function(cubic);
+        // 0 -> 1 -> 2 -> 9
+        assert_eq!(orbit.by_ref().last(), Some(Complex::new(9.0, 0.0)));
+        assert!(orbit.escaped());
     }
-    println!("Finished coming up with pixel values");
 
-    return imgs;
+    #[test]
+    fn escape_radius_leaves_bounded_orbits_beyond_2_alone() {
+        // 3 is a fixed point of z^1.5 + c for c = 3 - 3^1.5, so its orbit never escapes even
+        // though it's further than 2 from 0.
+        let c = Complex::new(3.0 - 3.0_f64.powf(1.5), 0.0);
+        let function = FormulaMap::new(Formula::Mandelbrot, 1.5);
+        let mut orbit = c
+            .orbit()
+            .with_function(function)
+            .starting_from(Complex::new(3.0, 0.0))
+            .check_cycles(false)
+            .max_iterations(10);
+        orbit.by_ref().count();
+        assert!(!orbit.escaped());
+        // Orbits of 0 only have to get beyond 2^(1/(1.5 - 1)) = 4.
+        assert!(!function.escaped(Complex::new(0.0, 4.0)));
+        assert!(function.escaped(Complex::new(0.0, 4.01)));
+
+        // The same goes for 2.5 in the Julia set of c = 2.5 - 2.5^2.
+        let c = Complex::new(2.5 - 2.5 * 2.5, 0.0);
+        let function = FormulaMap::new(Formula::Mandelbrot, MANDELBROT_EXPONENT);
+        assert!(function.escaped(Complex::new(2.5, 0.0)));
+        let mut orbit = c
+            .orbit()
+            .with_function(function.for_julia(c))
+            .starting_from(Complex::new(2.5, 0.0))
+            .check_cycles(false)
+            .max_iterations(10);
+        orbit.by_ref().count();
+        assert!(!orbit.escaped());
+        // Saved maps don't keep the escape radius, so they're equal whatever the Julia constant.
+        assert_eq!(function.for_julia(c), function);
+    }
+
+    #[test]
+    fn render_buddhabort_output_does_not_depend_on_threads() {
+        let render = |thread_count: usize| {
+            let mut path = std::env::temp_dir();
+            path.push(format!(
+                "buddhabrot-render-test-{}-{}.json",
+                std::process::id(),
+                thread_count
+            ));
+            let json_file = path.to_str().unwrap().to_string();
+            let imgs = render_buddhabort(Conf {
+                json_file: json_file.clone(),
+                thread_count,
+                max_iterations: 200,
+                min_iterations: 20,
+                width: 32,
+                height: 24,
+                samplescale: 1.0,
+                centerx: -0.5,
+                centery: 0.0,
+                zoomlevel: 0.0,
+                trajectory_count: 40,
+                stall_timeout: None,
+                seed: 7,
+                orbits: OrbitKind::Escaping,
+                formula: Formula::Mandelbrot,
+                exponent: MANDELBROT_EXPONENT,
+                julia: None,
+                interior_mask: None,
+                itercounts_file: Some(path.with_extension("txt")),
+            });
+            let json = std::fs::read_to_string(&json_file).unwrap();
+            let itercounts = std::fs::read_to_string(path.with_extension("txt")).unwrap();
+            std::fs::remove_file(&json_file).unwrap();
+            std::fs::remove_file(path.with_extension("txt")).unwrap();
+            assert!(!itercounts.is_empty());
+            (json, imgs)
+        };
+        let (one_json, one_imgs) = render(1);
+        let (three_json, three_imgs) = render(3);
+
+        assert_eq!(one_json.lines().count(), 40);
+        assert_eq!(one_json, three_json);
+        for (one, three) in one_imgs.iter().zip(three_imgs.iter()) {
+            assert_eq!(one.pixels(), three.pixels());
+        }
+    }
 }
@@ -0,0 +1,339 @@
+//! A compact, lossless binary archive of raw accumulator counts. Unlike PPM, which is ASCII and
+//! limited to values of 65535, a histogram archive keeps every count exactly so that an expensive
+//! render can be re-tone-mapped or merged with other renders later without iterating any orbits.
+//!
+//! All integers are little-endian. An archive is laid out as:
+//!
+//! ```text
+//! magic          8 bytes, "BBRTHIST"
+//! version        u32
+//! width          u32
+//! height         u32
+//! channel count  u32
+//! pixel type     u32, 1 for u32 pixels, 2 for i64 pixels or 3 for f64 pixels
+//! params length  u32
+//! params         `params length` bytes of JSON holding the RenderParams
+//! pixels         each channel in turn, each in row-major order
+//! ```
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+
+use serde::de::DeserializeOwned;
+use serde_json;
+
+use ppm::Img;
+use viewport::Viewport;
+
+const MAGIC: &[u8; 8] = b"BBRTHIST";
+pub const VERSION: u32 = 1;
+
+/// How the pixels of an archive are stored. Archives use the smallest type which can hold every
+/// count, falling back to f64 for the fractional sums left by weighted trajectories.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PixelType {
+    U32,
+    I64,
+    F64,
+}
+
+impl PixelType {
+    fn code(self) -> u32 {
+        match self {
+            PixelType::U32 => 1,
+            PixelType::I64 => 2,
+            PixelType::F64 => 3,
+        }
+    }
+    fn from_code(code: u32) -> io::Result<PixelType> {
+        match code {
+            1 => Ok(PixelType::U32),
+            2 => Ok(PixelType::I64),
+            3 => Ok(PixelType::F64),
+            _ => Err(invalid(format!("unknown pixel type {}", code))),
+        }
+    }
+    fn fits(imgs: &[Img]) -> PixelType {
+        let pixels = || imgs.iter().flat_map(|img| img.pixels().iter());
+        let whole = pixels().all(|px| px.fract() == 0.0 && px.abs() < i64::MAX as f64);
+        if !whole {
+            PixelType::F64
+        } else if pixels().all(|&px| px >= 0.0 && px <= u32::MAX as f64) {
+            PixelType::U32
+        } else {
+            PixelType::I64
+        }
+    }
+}
+
+/// The parameters a set of histograms was rendered with, stored alongside the counts so a later
+/// run knows what it's looking at.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct RenderParams {
+    pub viewport: Viewport,
+    pub min_iterations: i64,
+    pub max_iterations: i64,
+    pub trajectory_count: i64,
+}
+
+pub(crate) fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+pub(crate) fn read_u32<R: Read>(r: &mut R) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+// The longest block of JSON parameters any of the binary formats will read. Real parameters take
+// a few hundred bytes, so anything longer can only come from a corrupt file.
+const MAX_PARAMS_LEN: usize = 1 << 16;
+
+// The most items read_items makes room for before any of them have been read.
+const MAX_PREALLOCATED: usize = 1 << 16;
+
+/// Reads exactly `len` bytes. The buffer grows as the bytes arrive, so a length taken from a
+/// corrupt header fails at the end of the file instead of allocating all of it up front.
+pub(crate) fn read_bytes<R: Read>(r: &mut R, len: usize) -> io::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    r.by_ref().take(len as u64).read_to_end(&mut bytes)?;
+    if bytes.len() < len {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("expected {} bytes but found {}", len, bytes.len()),
+        ));
+    }
+    Ok(bytes)
+}
+
+/// Reads the parameters the binary formats store after their headers: a u32 length followed by
+/// that many bytes of JSON.
+pub(crate) fn read_params<R: Read, T: DeserializeOwned>(r: &mut R) -> io::Result<T> {
+    let len = read_u32(r)? as usize;
+    if len > MAX_PARAMS_LEN {
+        return Err(invalid(format!("{} bytes of parameters is too many", len)));
+    }
+    let json = read_bytes(r, len)?;
+    serde_json::from_slice(&json).map_err(|e| invalid(e.to_string()))
+}
+
+/// Reads `count` items with `read_item`. Like `read_bytes`, this doesn't trust `count` with the
+/// allocation, so a corrupt count fails once the file runs out.
+pub(crate) fn read_items<R, T, F>(r: &mut R, count: usize, mut read_item: F) -> io::Result<Vec<T>>
+where
+    R: Read,
+    F: FnMut(&mut R) -> io::Result<T>,
+{
+    let mut items = Vec::with_capacity(count.min(MAX_PREALLOCATED));
+    for _ in 0..count {
+        items.push(read_item(r)?);
+    }
+    Ok(items)
+}
+
+/// Writes `imgs` and `params` as a histogram archive. Every channel must have the same
+/// dimensions.
+pub fn write_histograms<W: Write>(
+    w: &mut W,
+    imgs: &[Img],
+    params: &RenderParams,
+) -> io::Result<()> {
+    let (width, height) = match imgs.first() {
+        Some(img) => (img.width(), img.height()),
+        None => return Err(invalid("no channels to write".to_string())),
+    };
+    if imgs
+        .iter()
+        .any(|img| img.width() != width || img.height() != height)
+    {
+        return Err(invalid("channels have mismatched dimensions".to_string()));
+    }
+    let pixel_type = PixelType::fits(imgs);
+    let params_json = serde_json::to_vec(params).map_err(|e| invalid(e.to_string()))?;
+
+    w.write_all(MAGIC)?;
+    for field in [
+        VERSION,
+        width as u32,
+        height as u32,
+        imgs.len() as u32,
+        pixel_type.code(),
+        params_json.len() as u32,
+    ]
+    .iter()
+    {
+        w.write_all(&field.to_le_bytes())?;
+    }
+    w.write_all(&params_json)?;
+    for img in imgs {
+        for &px in img.pixels() {
+            match pixel_type {
+                PixelType::U32 => w.write_all(&(px as u32).to_le_bytes())?,
+                PixelType::I64 => w.write_all(&(px as i64).to_le_bytes())?,
+                PixelType::F64 => w.write_all(&px.to_le_bytes())?,
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Reads a histogram archive, returning each of its channels along with the parameters it was
+/// rendered with.
+pub fn read_histograms<R: Read>(r: &mut R) -> io::Result<(Vec<Img>, RenderParams)> {
+    let mut magic = [0; 8];
+    r.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid("not a histogram archive".to_string()));
+    }
+    let version = read_u32(r)?;
+    if version != VERSION {
+        return Err(invalid(format!(
+            "unsupported histogram archive version {}",
+            version
+        )));
+    }
+    let width = read_u32(r)? as i64;
+    let height = read_u32(r)? as i64;
+    let channels = read_u32(r)?;
+    let pixel_type = PixelType::from_code(read_u32(r)?)?;
+    let params: RenderParams = read_params(r)?;
+    let count = width
+        .checked_mul(height)
+        .ok_or_else(|| invalid(format!("a {}x{} image is too large", width, height)))?;
+
+    let mut imgs = Vec::new();
+    for _ in 0..channels {
+        let pixels = read_items(r, count as usize, |r| {
+            Ok(match pixel_type {
+                PixelType::U32 => read_u32(r)? as f64,
+                PixelType::I64 => {
+                    let mut buf = [0; 8];
+                    r.read_exact(&mut buf)?;
+                    i64::from_le_bytes(buf) as f64
+                }
+                PixelType::F64 => {
+                    let mut buf = [0; 8];
+                    r.read_exact(&mut buf)?;
+                    f64::from_le_bytes(buf)
+                }
+            })
+        })?;
+        imgs.push(Img::from_pixels(width, height, pixels));
+    }
+    Ok((imgs, params))
+}
+
+/// Saves `imgs` to the file `fname` as a histogram archive.
+pub fn save_histograms(imgs: &[Img], params: &RenderParams, fname: String) -> io::Result<()> {
+    let mut w = BufWriter::new(File::create(fname)?);
+    write_histograms(&mut w, imgs, params)?;
+    w.flush()
+}
+
+/// Loads the histogram archive in the file `fname`.
+pub fn load_histograms(fname: String) -> io::Result<(Vec<Img>, RenderParams)> {
+    let mut r = BufReader::new(File::open(fname)?);
+    read_histograms(&mut r)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use buddha::Complex;
+
+    fn params() -> RenderParams {
+        RenderParams {
+            viewport: Viewport::new(Complex::new(-0.5, 0.25), 4.0, 16.0 / 9.0, 0.5),
+            min_iterations: 1000,
+            max_iterations: 50000,
+            trajectory_count: 12,
+        }
+    }
+
+    fn round_trip(imgs: &[Img]) -> (Vec<Img>, RenderParams) {
+        let mut buf = Vec::new();
+        write_histograms(&mut buf, imgs, &params()).unwrap();
+        read_histograms(&mut &buf[..]).unwrap()
+    }
+
+    #[test]
+    fn round_trip_preserves_counts_and_params() {
+        let mut imgs = vec![Img::new(16, 9), Img::new(16, 9), Img::new(16, 9)];
+        imgs[0].set_px(15, 8, 70000.0);
+        imgs[1].set_px(3, 2, 1.0);
+        imgs[2].set_px(0, 0, 5.0);
+        let (read, read_params) = round_trip(&imgs);
+
+        assert_eq!(PixelType::fits(&imgs), PixelType::U32);
+        assert_eq!(read.len(), 3);
+        for (orig, img) in imgs.iter().zip(read.iter()) {
+            assert_eq!(img.width(), 16);
+            assert_eq!(img.height(), 9);
+            assert_eq!(img.pixels(), orig.pixels());
+        }
+        assert_eq!(read[0].maximum(), 70000.0);
+        assert_eq!(read_params.viewport.zoom, 4.0);
+        assert_eq!(read_params.viewport.center.im, 0.25);
+        assert_eq!(read_params.max_iterations, 50000);
+        assert_eq!(read_params.trajectory_count, 12);
+    }
+
+    #[test]
+    fn round_trip_preserves_counts_beyond_u32() {
+        let mut imgs = vec![Img::new(2, 3)];
+        imgs[0].set_px(1, 2, u32::MAX as f64 + 10.0);
+        let (read, _) = round_trip(&imgs);
+
+        assert_eq!(PixelType::fits(&imgs), PixelType::I64);
+        assert_eq!(read[0].get_px(1, 2), Some(u32::MAX as f64 + 10.0));
+    }
+
+    #[test]
+    fn round_trip_preserves_weighted_sums() {
+        let mut imgs = vec![Img::new(3, 2)];
+        imgs[0].set_px(2, 1, 0.1 + 0.2);
+        imgs[0].set_px(0, 0, 12.0);
+        let (read, _) = round_trip(&imgs);
+
+        assert_eq!(PixelType::fits(&imgs), PixelType::F64);
+        assert_eq!(read[0].pixels(), imgs[0].pixels());
+    }
+
+    #[test]
+    fn rejects_other_files_and_versions() {
+        let mut buf = Vec::new();
+        write_histograms(&mut buf, &[Img::new(2, 2)], &params()).unwrap();
+
+        let mut bad_magic = buf.clone();
+        bad_magic[0] = b'P';
+        assert!(read_histograms(&mut &bad_magic[..]).is_err());
+
+        let mut bad_version = buf.clone();
+        bad_version[8..12].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert!(read_histograms(&mut &bad_version[..]).is_err());
+
+        assert!(read_histograms(&mut &buf[..buf.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn rejects_headers_larger_than_the_archive() {
+        let mut buf = Vec::new();
+        write_histograms(&mut buf, &[Img::new(2, 2)], &params()).unwrap();
+
+        // Huge dimensions run out of pixels instead of allocating room for all of them.
+        let mut huge = buf.clone();
+        huge[12..16].copy_from_slice(&65535u32.to_le_bytes());
+        huge[16..20].copy_from_slice(&65535u32.to_le_bytes());
+        huge[20..24].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(read_histograms(&mut &huge[..]).is_err());
+
+        let mut overflowing = buf.clone();
+        overflowing[12..20].copy_from_slice(&[0xff; 8]);
+        assert!(read_histograms(&mut &overflowing[..]).is_err());
+
+        let mut long_params = buf.clone();
+        long_params[28..32].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(read_histograms(&mut &long_params[..]).is_err());
+    }
+}
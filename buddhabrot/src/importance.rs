@@ -0,0 +1,352 @@
+//! An importance map is a coarse grid over the rectangle points are sampled from, recording how
+//! many of the points tried in each cell gave orbits lasting between the minimum and maximum
+//! number of iterations. Sampling cells in proportion to those counts spends far less time on
+//! the large stretches of the plane which never give a trajectory worth keeping.
+//!
+//! Every sample comes with a weight: how much less likely the map made its point than sampling
+//! the whole rectangle uniformly would have. Scaling each trajectory by its weight gives the same
+//! expected image as uniform sampling. Cells where nothing was found still get a share of the
+//! samples, so that no part of the rectangle is out of reach and no weight grows without bound.
+//!
+//! Maps may be saved to and loaded from files, laid out like histogram archives:
+//!
+//! ```text
+//! magic          8 bytes, "BBRTIMAP"
+//! version        u32
+//! width          u32
+//! height         u32
+//! samples        u32, the points tried along each side of every cell
+//! params length  u32
+//! params         `params length` bytes of JSON holding the ImportanceParams
+//! hits           u32 for each cell in row-major order
+//! ```
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::thread;
+
+use rand::Rng;
+use serde_json;
+
+use buddha::{Complex, FormulaMap, IterationFunction};
+use histogram::{invalid, read_items, read_params, read_u32};
+
+const MAGIC: &[u8; 8] = b"BBRTIMAP";
+pub const VERSION: u32 = 1;
+
+/// The share of samples spread over the cells in which the pre-pass found nothing.
+const EMPTY_SHARE: f64 = 0.1;
+
+/// What an importance map was built for: the function iterated, the Julia constant if a Julia
+/// set was searched (in which case the rectangle holds starting `z` values rather than `c`), the
+/// band of orbit lengths which counted, and the rectangle covered.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct ImportanceParams {
+    pub function: FormulaMap,
+    pub julia: Option<Complex>,
+    pub min_iterations: i64,
+    pub max_iterations: i64,
+    pub re_min: f64,
+    pub re_max: f64,
+    pub im_min: f64,
+    pub im_max: f64,
+}
+
+pub struct ImportanceMap {
+    params: ImportanceParams,
+    width: usize,
+    height: usize,
+    samples: usize,
+    hits: Vec<u32>,
+    // The running total of the sampling weight of every cell up to and including each one.
+    cumulative: Vec<f64>,
+}
+
+impl ImportanceMap {
+    /// Builds a `width` x `height` map over the rectangle given in `params`, trying `samples` x
+    /// `samples` evenly spaced points in each cell and spreading the work over `thread_count`
+    /// threads.
+    pub fn build(
+        params: ImportanceParams,
+        width: usize,
+        height: usize,
+        samples: usize,
+        thread_count: usize,
+    ) -> ImportanceMap {
+        let mut hits = vec![0; width * height];
+        let rows_per_thread = height.div_ceil(thread_count.max(1)).max(1);
+        thread::scope(|scope| {
+            for (chunk, rows) in hits.chunks_mut(rows_per_thread * width).enumerate() {
+                scope.spawn(move || {
+                    for (i, cell) in rows.iter_mut().enumerate() {
+                        let x = i % width;
+                        let y = chunk * rows_per_thread + i / width;
+                        *cell = count_hits(&params, width, height, samples, x, y);
+                    }
+                });
+            }
+        });
+        ImportanceMap::from_hits(params, width, height, samples, hits)
+    }
+
+    fn from_hits(
+        params: ImportanceParams,
+        width: usize,
+        height: usize,
+        samples: usize,
+        hits: Vec<u32>,
+    ) -> ImportanceMap {
+        let total: f64 = hits.iter().map(|&h| h as f64).sum();
+        let empty = hits.iter().filter(|&&h| h == 0).count();
+        // The weight each empty cell gets so that together they make up `EMPTY_SHARE` of the
+        // total. Without any hits at all every cell is empty, and the map samples uniformly.
+        let empty_weight = if total == 0.0 {
+            1.0
+        } else if empty == 0 {
+            0.0
+        } else {
+            total * EMPTY_SHARE / ((1.0 - EMPTY_SHARE) * empty as f64)
+        };
+        let mut sum = 0.0;
+        let cumulative = hits
+            .iter()
+            .map(|&h| {
+                sum += if h == 0 { empty_weight } else { h as f64 };
+                sum
+            })
+            .collect();
+        ImportanceMap {
+            params,
+            width,
+            height,
+            samples,
+            hits,
+            cumulative,
+        }
+    }
+
+    pub fn params(&self) -> &ImportanceParams {
+        &self.params
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// The number of points tried along each side of every cell.
+    pub fn samples(&self) -> usize {
+        self.samples
+    }
+
+    /// The number of points tried in the cell at (`x`, `y`) which gave orbits in the band.
+    pub fn hits(&self, x: usize, y: usize) -> u32 {
+        self.hits[y * self.width + x]
+    }
+
+    /// The fraction of the cells with at least one hit.
+    pub fn coverage(&self) -> f64 {
+        self.hits.iter().filter(|&&h| h > 0).count() as f64 / self.hits.len().max(1) as f64
+    }
+
+    /// Draws a point from the map, returning it along with its weight: the probability density
+    /// of drawing it uniformly from the rectangle divided by the probability density of drawing
+    /// it from the map.
+    pub fn sample<R: Rng>(&self, rng: &mut R) -> (Complex, f64) {
+        let total = *self.cumulative.last().unwrap_or(&0.0);
+        let target = rng.gen::<f64>() * total;
+        let cell = self
+            .cumulative
+            .partition_point(|&w| w <= target)
+            .min(self.cumulative.len() - 1);
+        let before = if cell > 0 { self.cumulative[cell - 1] } else { 0.0 };
+        let cell_weight = self.cumulative[cell] - before;
+        let (x, y) = (cell % self.width, cell / self.width);
+        let point = self.point_at(x as f64 + rng.gen::<f64>(), y as f64 + rng.gen::<f64>());
+        let weight = total / (self.cumulative.len() as f64 * cell_weight);
+        (point, weight)
+    }
+
+    // Returns the point at the continuous cell coordinates (`x`, `y`).
+    fn point_at(&self, x: f64, y: f64) -> Complex {
+        point_at(&self.params, self.width, self.height, x, y)
+    }
+}
+
+fn point_at(params: &ImportanceParams, width: usize, height: usize, x: f64, y: f64) -> Complex {
+    Complex::new(
+        params.re_min + (params.re_max - params.re_min) * x / width as f64,
+        params.im_min + (params.im_max - params.im_min) * y / height as f64,
+    )
+}
+
+// Counts the evenly spaced points in the cell at (`x`, `y`) whose orbits escape after lasting
+// between the minimum and maximum number of iterations.
+fn count_hits(
+    params: &ImportanceParams,
+    width: usize,
+    height: usize,
+    samples: usize,
+    x: usize,
+    y: usize,
+) -> u32 {
+    let mut hits = 0;
+    for j in 0..samples {
+        for i in 0..samples {
+            let point = point_at(
+                params,
+                width,
+                height,
+                x as f64 + (i as f64 + 0.5) / samples as f64,
+                y as f64 + (j as f64 + 0.5) / samples as f64,
+            );
+            let (z, c, function) = match params.julia {
+                Some(c) => (point, c, params.function.for_julia(c)),
+                None => (Complex::new(0.0, 0.0), point, params.function),
+            };
+            if params.julia.is_none() && function.known_interior(c) {
+                continue;
+            }
+            let mut orbit = c
+                .orbit()
+                .with_function(function)
+                .starting_from(z)
+                .max_iterations(params.max_iterations);
+            orbit.by_ref().count();
+            if orbit.escaped() && orbit.iterations() >= params.min_iterations {
+                hits += 1;
+            }
+        }
+    }
+    hits
+}
+
+/// Writes `map` in the importance map file format.
+pub fn write_importance_map<W: Write>(w: &mut W, map: &ImportanceMap) -> io::Result<()> {
+    let params_json = serde_json::to_vec(&map.params).map_err(|e| invalid(e.to_string()))?;
+    w.write_all(MAGIC)?;
+    for field in [
+        VERSION,
+        map.width as u32,
+        map.height as u32,
+        map.samples as u32,
+        params_json.len() as u32,
+    ]
+    .iter()
+    {
+        w.write_all(&field.to_le_bytes())?;
+    }
+    w.write_all(&params_json)?;
+    for &h in &map.hits {
+        w.write_all(&h.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+/// Reads a map written by `write_importance_map`.
+pub fn read_importance_map<R: Read>(r: &mut R) -> io::Result<ImportanceMap> {
+    let mut magic = [0; 8];
+    r.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid("not an importance map".to_string()));
+    }
+    let version = read_u32(r)?;
+    if version != VERSION {
+        return Err(invalid(format!(
+            "unsupported importance map version {}",
+            version
+        )));
+    }
+    let width = read_u32(r)? as usize;
+    let height = read_u32(r)? as usize;
+    let samples = read_u32(r)? as usize;
+    if width == 0 || height == 0 {
+        return Err(invalid("importance map has no cells".to_string()));
+    }
+    let params: ImportanceParams = read_params(r)?;
+    let hits = read_items(r, width * height, read_u32)?;
+    Ok(ImportanceMap::from_hits(
+        params, width, height, samples, hits,
+    ))
+}
+
+/// Saves `map` to the file `fname`.
+pub fn save_importance_map(map: &ImportanceMap, fname: String) -> io::Result<()> {
+    let mut w = BufWriter::new(File::create(fname)?);
+    write_importance_map(&mut w, map)?;
+    w.flush()
+}
+
+/// Loads the importance map in the file `fname`.
+pub fn load_importance_map(fname: String) -> io::Result<ImportanceMap> {
+    let mut r = BufReader::new(File::open(fname)?);
+    read_importance_map(&mut r)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use buddha::{unit_rng, Formula, MANDELBROT_EXPONENT};
+
+    fn params() -> ImportanceParams {
+        ImportanceParams {
+            function: FormulaMap::new(Formula::Mandelbrot, MANDELBROT_EXPONENT),
+            julia: None,
+            min_iterations: 20,
+            max_iterations: 1000,
+            re_min: -2.5,
+            re_max: 1.0,
+            im_min: -1.0,
+            im_max: 1.0,
+        }
+    }
+
+    #[test]
+    fn map_favors_the_boundary_without_bias() {
+        let map = ImportanceMap::build(params(), 35, 20, 4, 3);
+        // Deep inside the main cardioid, and far outside of the set.
+        assert_eq!(map.hits(21, 10), 0);
+        assert_eq!(map.hits(0, 0), 0);
+        let coverage = map.coverage();
+        assert!(coverage > 0.05 && coverage < 0.6, "coverage {}", coverage);
+
+        // Weights undo the map's preferences, so on average they come to 1.
+        let mut rng = unit_rng(1, 0);
+        let mut total = 0.0;
+        let mut near_boundary = 0;
+        let n = 100000;
+        for _ in 0..n {
+            let (c, weight) = map.sample(&mut rng);
+            assert!(c.re >= -2.5 && c.re < 1.0 && c.im >= -1.0 && c.im < 1.0);
+            total += weight;
+            let x = ((c.re + 2.5) / 0.1) as usize;
+            let y = ((c.im + 1.0) / 0.1) as usize;
+            if map.hits(x, y) > 0 {
+                near_boundary += 1;
+            }
+        }
+        assert!(
+            (total / n as f64 - 1.0).abs() < 0.05,
+            "mean weight {}",
+            total / n as f64
+        );
+        assert!(near_boundary as f64 > 0.85 * n as f64);
+    }
+
+    #[test]
+    fn map_round_trips() {
+        let map = ImportanceMap::build(params(), 14, 8, 2, 1);
+        let mut buf = Vec::new();
+        write_importance_map(&mut buf, &map).unwrap();
+        let read = read_importance_map(&mut &buf[..]).unwrap();
+        assert_eq!(read.params(), map.params());
+        assert_eq!((read.width(), read.height(), read.samples()), (14, 8, 2));
+        assert_eq!(read.hits, map.hits);
+        assert_eq!(read.cumulative, map.cumulative);
+
+        buf[0] = b'P';
+        assert!(read_importance_map(&mut &buf[..]).is_err());
+    }
+}
@@ -0,0 +1,304 @@
+//! An interior mask is a low resolution grid over a rectangle of the c-plane, marking the cells
+//! which lie entirely inside the set. Searches consult it to skip points which would otherwise
+//! burn through every iteration without escaping, on top of the analytic tests in
+//! `will_loop_forever`.
+//!
+//! A cell is marked as interior when every point sampled along its edges, and along the edges of
+//! the eight cells around it, stays bounded. Because the set has no holes, a cell whose whole
+//! boundary lies inside the set lies inside it too, so the mask only gets it wrong when a filament
+//! of the exterior slips between the samples; finer masks make that less likely.
+//!
+//! Masks may be saved to and loaded from files, laid out like histogram archives:
+//!
+//! ```text
+//! magic          8 bytes, "BBRTMASK"
+//! version        u32
+//! width          u32
+//! height         u32
+//! params length  u32
+//! params         `params length` bytes of JSON holding the MaskParams
+//! cells          one bit per cell in row-major order, lowest bit first, 1 for interior
+//! ```
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::sync::Arc;
+use std::thread;
+
+use serde_json;
+
+use buddha::{Complex, FormulaMap, IterationFunction};
+use histogram::{invalid, read_bytes, read_params, read_u32};
+
+const MAGIC: &[u8; 8] = b"BBRTMASK";
+pub const VERSION: u32 = 1;
+
+/// The number of points sampled along each edge of a cell when building a mask.
+const EDGE_SAMPLES: usize = 4;
+
+/// What an interior mask was built for: the function whose orbits were iterated, the number of
+/// iterations an orbit had to stay bounded for, and the rectangle of the c-plane covered.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct MaskParams {
+    pub function: FormulaMap,
+    pub max_iterations: i64,
+    pub re_min: f64,
+    pub re_max: f64,
+    pub im_min: f64,
+    pub im_max: f64,
+}
+
+pub struct InteriorMask {
+    params: MaskParams,
+    width: usize,
+    height: usize,
+    cells: Vec<bool>,
+}
+
+impl InteriorMask {
+    /// Builds a `width` x `height` mask over the rectangle given in `params`, spreading the work
+    /// over `thread_count` threads.
+    pub fn build(
+        params: MaskParams,
+        width: usize,
+        height: usize,
+        thread_count: usize,
+    ) -> InteriorMask {
+        // The points sampled along the edges of every cell form a lattice, with neighbouring cells
+        // sharing the points along their common edge.
+        let lattice_width = width * EDGE_SAMPLES + 1;
+        let lattice_height = height * EDGE_SAMPLES + 1;
+        let mut bounded = vec![false; lattice_width * lattice_height];
+        let rows_per_thread = lattice_height.div_ceil(thread_count.max(1));
+        thread::scope(|scope| {
+            for (chunk, rows) in bounded
+                .chunks_mut(rows_per_thread * lattice_width)
+                .enumerate()
+            {
+                scope.spawn(move || {
+                    for (i, point) in rows.iter_mut().enumerate() {
+                        let x = i % lattice_width;
+                        let y = chunk * rows_per_thread + i / lattice_width;
+                        let c = Complex::new(
+                            params.re_min
+                                + (params.re_max - params.re_min) * x as f64
+                                    / (lattice_width - 1) as f64,
+                            params.im_min
+                                + (params.im_max - params.im_min) * y as f64
+                                    / (lattice_height - 1) as f64,
+                        );
+                        *point = stays_bounded(&params, c);
+                    }
+                });
+            }
+        });
+
+        // A cell's own samples can miss a thin filament of the exterior reaching into it, so a
+        // cell is only interior when every cell around it passed as well.
+        let mut edges_bounded = vec![false; width * height];
+        for y in 0..height {
+            for x in 0..width {
+                let (left, top) = (x * EDGE_SAMPLES, y * EDGE_SAMPLES);
+                let (right, bottom) = (left + EDGE_SAMPLES, top + EDGE_SAMPLES);
+                let at = |lx: usize, ly: usize| bounded[ly * lattice_width + lx];
+                edges_bounded[y * width + x] = (left..=right)
+                    .all(|lx| at(lx, top) && at(lx, bottom))
+                    && (top..=bottom).all(|ly| at(left, ly) && at(right, ly));
+            }
+        }
+        let mut cells = vec![false; width * height];
+        for y in 1..height.saturating_sub(1) {
+            for x in 1..width.saturating_sub(1) {
+                cells[y * width + x] = (y - 1..=y + 1)
+                    .all(|ny| (x - 1..=x + 1).all(|nx| edges_bounded[ny * width + nx]));
+            }
+        }
+        InteriorMask {
+            params,
+            width,
+            height,
+            cells,
+        }
+    }
+
+    pub fn params(&self) -> &MaskParams {
+        &self.params
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// The fraction of the cells which are interior.
+    pub fn coverage(&self) -> f64 {
+        self.cells.iter().filter(|&&cell| cell).count() as f64 / self.cells.len().max(1) as f64
+    }
+
+    /// Reports whether `c` lies in an interior cell. Points outside of the mask never do.
+    pub fn contains(&self, c: Complex) -> bool {
+        let u = (c.re - self.params.re_min) / (self.params.re_max - self.params.re_min);
+        let v = (c.im - self.params.im_min) / (self.params.im_max - self.params.im_min);
+        if !(0.0..1.0).contains(&u) || !(0.0..1.0).contains(&v) {
+            return false;
+        }
+        let x = (u * self.width as f64) as usize;
+        let y = (v * self.height as f64) as usize;
+        self.cells[y * self.width + x]
+    }
+}
+
+fn stays_bounded(params: &MaskParams, c: Complex) -> bool {
+    if params.function.known_interior(c) {
+        return true;
+    }
+    let mut orbit = c
+        .orbit()
+        .with_function(params.function)
+        .max_iterations(params.max_iterations);
+    orbit.by_ref().count();
+    !orbit.escaped()
+}
+
+/// Writes `mask` in the interior mask file format.
+pub fn write_mask<W: Write>(w: &mut W, mask: &InteriorMask) -> io::Result<()> {
+    let params_json = serde_json::to_vec(&mask.params).map_err(|e| invalid(e.to_string()))?;
+    w.write_all(MAGIC)?;
+    for field in [
+        VERSION,
+        mask.width as u32,
+        mask.height as u32,
+        params_json.len() as u32,
+    ]
+    .iter()
+    {
+        w.write_all(&field.to_le_bytes())?;
+    }
+    w.write_all(&params_json)?;
+    let mut bits = vec![0u8; mask.cells.len().div_ceil(8)];
+    for (i, _) in mask.cells.iter().enumerate().filter(|&(_, &cell)| cell) {
+        bits[i / 8] |= 1 << (i % 8);
+    }
+    w.write_all(&bits)
+}
+
+/// Reads a mask written by `write_mask`.
+pub fn read_mask<R: Read>(r: &mut R) -> io::Result<InteriorMask> {
+    let mut magic = [0; 8];
+    r.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid("not an interior mask".to_string()));
+    }
+    let version = read_u32(r)?;
+    if version != VERSION {
+        return Err(invalid(format!(
+            "unsupported interior mask version {}",
+            version
+        )));
+    }
+    let width = read_u32(r)? as usize;
+    let height = read_u32(r)? as usize;
+    let params: MaskParams = read_params(r)?;
+    let bits = read_bytes(r, (width * height).div_ceil(8))?;
+    let cells = (0..width * height)
+        .map(|i| bits[i / 8] & (1 << (i % 8)) != 0)
+        .collect();
+    Ok(InteriorMask {
+        params,
+        width,
+        height,
+        cells,
+    })
+}
+
+/// Saves `mask` to the file `fname`.
+pub fn save_mask(mask: &InteriorMask, fname: String) -> io::Result<()> {
+    let mut w = BufWriter::new(File::create(fname)?);
+    write_mask(&mut w, mask)?;
+    w.flush()
+}
+
+/// Loads the interior mask in the file `fname`.
+pub fn load_mask(fname: String) -> io::Result<InteriorMask> {
+    let mut r = BufReader::new(File::open(fname)?);
+    read_mask(&mut r)
+}
+
+/// Masked wraps an iteration function so that the points inside an interior mask are known to
+/// be interior as well. Without a mask it behaves just like the function it wraps.
+#[derive(Clone)]
+pub struct Masked<F> {
+    pub function: F,
+    pub mask: Option<Arc<InteriorMask>>,
+}
+
+impl<F: IterationFunction> Masked<F> {
+    pub fn new(function: F, mask: Option<Arc<InteriorMask>>) -> Masked<F> {
+        Masked { function, mask }
+    }
+}
+
+impl<F: IterationFunction> IterationFunction for Masked<F> {
+    #[inline]
+    fn step(&self, z: Complex, c: Complex) -> Complex {
+        self.function.step(z, c)
+    }
+
+    fn escaped(&self, z: Complex) -> bool {
+        self.function.escaped(z)
+    }
+
+    fn known_interior(&self, c: Complex) -> bool {
+        self.function.known_interior(c) || self.mask.as_ref().is_some_and(|m| m.contains(c))
+    }
+
+    fn distance_estimate(&self, c: Complex, iterations: i64) -> Option<f64> {
+        self.function.distance_estimate(c, iterations)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use buddha::{Formula, MANDELBROT_EXPONENT};
+
+    fn params() -> MaskParams {
+        MaskParams {
+            function: FormulaMap::new(Formula::Mandelbrot, MANDELBROT_EXPONENT),
+            max_iterations: 500,
+            re_min: -2.5,
+            re_max: 1.0,
+            im_min: -1.0,
+            im_max: 1.0,
+        }
+    }
+
+    #[test]
+    fn mask_covers_the_interior_only() {
+        let mask = InteriorMask::build(params(), 70, 40, 3);
+        assert!(mask.contains(Complex::new(-0.1, 0.0)));
+        assert!(mask.contains(Complex::new(-1.0, 0.0)));
+        assert!(!mask.contains(Complex::new(0.5, 0.5)));
+        assert!(!mask.contains(Complex::new(-2.4, 0.9)));
+        // Outside of the mask entirely.
+        assert!(!mask.contains(Complex::new(-0.1, 3.0)));
+        let coverage = mask.coverage();
+        assert!(coverage > 0.05 && coverage < 0.5, "coverage {}", coverage);
+    }
+
+    #[test]
+    fn mask_round_trips() {
+        let mask = InteriorMask::build(params(), 35, 20, 1);
+        let mut buf = Vec::new();
+        write_mask(&mut buf, &mask).unwrap();
+        let read = read_mask(&mut &buf[..]).unwrap();
+        assert_eq!(read.params(), mask.params());
+        assert_eq!((read.width(), read.height()), (35, 20));
+        assert_eq!(read.cells, mask.cells);
+
+        buf[0] = b'P';
+        assert!(read_mask(&mut &buf[..]).is_err());
+    }
+}
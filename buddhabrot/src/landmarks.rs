@@ -0,0 +1,396 @@
+//! Landmarks are the points of the c-plane which the longest escaping orbits crowd around: the
+//! centres of hyperbolic components, whose orbit of 0 comes back to 0 after `period` iterations,
+//! and Misiurewicz points, whose orbit of 0 lands on a cycle of length `period` after
+//! `preperiod` iterations. They're found with Newton's method on the iteration polynomial.
+//!
+//! Landmarks may be saved to and loaded from files, laid out like histogram archives:
+//!
+//! ```text
+//! magic          8 bytes, "BBRTLMKS"
+//! version        u32
+//! count          u32, the number of landmarks
+//! params length  u32
+//! params         `params length` bytes of JSON holding the LandmarkParams
+//! landmarks      for each landmark, the real and imaginary parts of c and its scale as f64, then
+//!                its preperiod and period as u32
+//! ```
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::thread;
+
+use serde_json;
+
+use buddha::{Complex, Formula, FormulaMap};
+use histogram::{invalid, read_items, read_params, read_u32};
+
+const MAGIC: &[u8; 8] = b"BBRTLMKS";
+pub const VERSION: u32 = 1;
+
+/// The number of points along each side of the grid Newton's method is started from.
+const NEWTON_GRID: usize = 128;
+const NEWTON_STEPS: usize = 64;
+
+/// Two roots closer together than this are the same landmark.
+const SAME_ROOT: f64 = 1e-9;
+
+/// What a set of landmarks was found for: the function iterated, the longest period and
+/// preperiod looked for, and the rectangle of the c-plane Newton's method was started from.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct LandmarkParams {
+    pub function: FormulaMap,
+    pub max_period: u32,
+    pub max_preperiod: u32,
+    pub re_min: f64,
+    pub re_max: f64,
+    pub im_min: f64,
+    pub im_max: f64,
+}
+
+/// A centre of a hyperbolic component (when `preperiod` is 0) or a Misiurewicz point.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Landmark {
+    pub c: Complex,
+    pub preperiod: u32,
+    pub period: u32,
+    /// Roughly how large the features of the set around this landmark are: the size of the
+    /// component for a centre, and the inverse of how fast the orbit moves with `c` by the time
+    /// it reaches its cycle for a Misiurewicz point.
+    pub scale: f64,
+}
+
+/// Reports whether landmarks can be found for `function`. Newton's method needs the map to be a
+/// polynomial in `z` and `c`, which only the Mandelbrot formula with a whole exponent is.
+pub fn supported(function: &FormulaMap) -> bool {
+    function.formula == Formula::Mandelbrot
+        && function.exponent.fract() == 0.0
+        && function.exponent >= 2.0
+}
+
+// Iterates the orbit of 0 under `c` for `n` steps, returning every point along with its
+// derivative with respect to `c`.
+fn orbit_with_derivatives(function: &FormulaMap, c: Complex, n: usize) -> Vec<(Complex, Complex)> {
+    let d = Complex::new(function.exponent, 0.0);
+    let mut z = Complex::new(0.0, 0.0);
+    let mut dz = Complex::new(0.0, 0.0);
+    let mut points = Vec::with_capacity(n + 1);
+    points.push((z, dz));
+    for _ in 0..n {
+        dz = d * z.pow(function.exponent - 1.0) * dz + Complex::new(1.0, 0.0);
+        z = z.pow(function.exponent) + c;
+        points.push((z, dz));
+    }
+    points
+}
+
+// Runs Newton's method on z_(preperiod + period) - z_preperiod = 0 from `start`, returning the
+// root it converges to, if it does.
+fn newton(
+    function: &FormulaMap,
+    start: Complex,
+    preperiod: usize,
+    period: usize,
+) -> Option<Complex> {
+    let mut c = start;
+    for _ in 0..NEWTON_STEPS {
+        let orbit = orbit_with_derivatives(function, c, preperiod + period);
+        let (z, dz) = orbit[preperiod + period];
+        let (w, dw) = orbit[preperiod];
+        let (g, dg) = (z - w, dz - dw);
+        let norm = dg.re * dg.re + dg.im * dg.im;
+        if norm == 0.0 || !norm.is_finite() {
+            return None;
+        }
+        // g / dg
+        let step = Complex::new(
+            (g.re * dg.re + g.im * dg.im) / norm,
+            (g.im * dg.re - g.re * dg.im) / norm,
+        );
+        c = c - step;
+        if !c.re.is_finite() || !c.im.is_finite() || c.norm() > 4.0 {
+            return None;
+        }
+        if step.norm() < 1e-15 * c.norm().max(1.0) {
+            return Some(c);
+        }
+    }
+    None
+}
+
+// Finds the smallest preperiod, and then the smallest period, the orbit of 0 under `c` settles
+// into, looking no further than the given limits. Returns the landmark `c` is, if any.
+fn classify(
+    function: &FormulaMap,
+    c: Complex,
+    max_preperiod: usize,
+    max_period: usize,
+) -> Option<Landmark> {
+    let orbit = orbit_with_derivatives(function, c, max_preperiod + max_period);
+    let tolerance = 1e-8;
+    for preperiod in 0..=max_preperiod {
+        for period in 1..=max_period {
+            if (orbit[preperiod + period].0 - orbit[preperiod].0).norm() < tolerance {
+                let scale = if preperiod == 0 {
+                    component_size(function, c, period)
+                } else {
+                    1.0 / orbit[preperiod].1.norm()
+                };
+                return Some(Landmark {
+                    c,
+                    preperiod: preperiod as u32,
+                    period: period as u32,
+                    scale,
+                });
+            }
+        }
+    }
+    None
+}
+
+// Estimates the size of the hyperbolic component whose centre of the given period is `c`, from
+// the multiplier of the cycle built up over its first `period - 1` steps.
+fn component_size(function: &FormulaMap, c: Complex, period: usize) -> f64 {
+    let d = Complex::new(function.exponent, 0.0);
+    let mut z = Complex::new(0.0, 0.0);
+    let mut l = Complex::new(1.0, 0.0);
+    let mut b = Complex::new(1.0, 0.0);
+    for _ in 1..period {
+        z = z.pow(function.exponent) + c;
+        l = d * z.pow(function.exponent - 1.0) * l;
+        let norm = l.re * l.re + l.im * l.im;
+        b = b + Complex::new(l.re / norm, -l.im / norm);
+    }
+    1.0 / (l * l * b).norm()
+}
+
+/// Finds the landmarks of every preperiod and period up to those given in `params`, by starting
+/// Newton's method from a grid of points over its rectangle and keeping each distinct root
+/// inside the rectangle. Landmarks whose roots no grid point happens to converge to are missed,
+/// which gets more likely the longer the periods are. The work is spread over `thread_count`
+/// threads, and the landmarks are returned in order of preperiod, period and position.
+pub fn find_landmarks(params: &LandmarkParams, thread_count: usize) -> Vec<Landmark> {
+    let max_preperiod = params.max_preperiod as usize;
+    let max_period = params.max_period as usize;
+    // Nothing has preperiod 1: z_(1 + p) = z_1 = c means z_p = 0, which makes c a centre.
+    // Newton's method for those orders only finds centres again, as double roots.
+    let orders: Vec<(usize, usize)> = (0..=max_preperiod)
+        .filter(|&k| k != 1)
+        .flat_map(|k| (1..=max_period).map(move |p| (k, p)))
+        .collect();
+    let per_thread = orders.len().div_ceil(thread_count.max(1)).max(1);
+    let found: Vec<Vec<Landmark>> = thread::scope(|scope| {
+        let threads: Vec<_> = orders
+            .chunks(per_thread)
+            .map(|chunk| {
+                scope.spawn(move || {
+                    let mut found = Vec::new();
+                    for &(preperiod, period) in chunk {
+                        for j in 0..NEWTON_GRID {
+                            for i in 0..NEWTON_GRID {
+                                let start = Complex::new(
+                                    params.re_min
+                                        + (params.re_max - params.re_min) * (i as f64 + 0.5)
+                                            / NEWTON_GRID as f64,
+                                    params.im_min
+                                        + (params.im_max - params.im_min) * (j as f64 + 0.5)
+                                            / NEWTON_GRID as f64,
+                                );
+                                let landmark = newton(&params.function, start, preperiod, period)
+                                    .and_then(|c| {
+                                        classify(&params.function, c, max_preperiod, max_period)
+                                    });
+                                // Roots of lower orders turn up too, but they're kept when
+                                // they're looked for themselves. The centres which are double
+                                // roots for longer preperiods only converge to within about
+                                // 1e-8, and are told apart by looking like preperiod 1.
+                                if let Some(landmark) = landmark {
+                                    if landmark.preperiod as usize == preperiod
+                                        && landmark.period as usize == period
+                                    {
+                                        found.push(landmark);
+                                    }
+                                }
+                            }
+                        }
+                    }
+                    found
+                })
+            })
+            .collect();
+        threads
+            .into_iter()
+            .map(|t| t.join().expect("a landmark thread panicked"))
+            .collect()
+    });
+
+    let mut landmarks: Vec<Landmark> = Vec::new();
+    let mut buckets: HashMap<(i64, i64), Vec<Complex>> = HashMap::new();
+    for landmark in found.into_iter().flatten() {
+        let c = landmark.c;
+        let inside = c.re >= params.re_min
+            && c.re < params.re_max
+            && c.im >= params.im_min
+            && c.im < params.im_max;
+        let (x, y) = (
+            (c.re / SAME_ROOT).floor() as i64,
+            (c.im / SAME_ROOT).floor() as i64,
+        );
+        let seen = (x - 1..=x + 1).any(|bx| {
+            (y - 1..=y + 1).any(|by| {
+                buckets
+                    .get(&(bx, by))
+                    .is_some_and(|roots| roots.iter().any(|&r| (r - c).norm() < SAME_ROOT))
+            })
+        });
+        if inside && !seen {
+            buckets.entry((x, y)).or_default().push(c);
+            landmarks.push(landmark);
+        }
+    }
+    landmarks.sort_by(|a, b| {
+        (a.preperiod, a.period)
+            .cmp(&(b.preperiod, b.period))
+            .then(a.c.re.total_cmp(&b.c.re))
+            .then(a.c.im.total_cmp(&b.c.im))
+    });
+    landmarks
+}
+
+/// Writes `landmarks` and the `params` they were found with in the landmark file format.
+pub fn write_landmarks<W: Write>(
+    w: &mut W,
+    params: &LandmarkParams,
+    landmarks: &[Landmark],
+) -> io::Result<()> {
+    let params_json = serde_json::to_vec(params).map_err(|e| invalid(e.to_string()))?;
+    w.write_all(MAGIC)?;
+    for field in [VERSION, landmarks.len() as u32, params_json.len() as u32].iter() {
+        w.write_all(&field.to_le_bytes())?;
+    }
+    w.write_all(&params_json)?;
+    for landmark in landmarks {
+        w.write_all(&landmark.c.re.to_le_bytes())?;
+        w.write_all(&landmark.c.im.to_le_bytes())?;
+        w.write_all(&landmark.scale.to_le_bytes())?;
+        w.write_all(&landmark.preperiod.to_le_bytes())?;
+        w.write_all(&landmark.period.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+fn read_f64<R: Read>(r: &mut R) -> io::Result<f64> {
+    let mut buf = [0; 8];
+    r.read_exact(&mut buf)?;
+    Ok(f64::from_le_bytes(buf))
+}
+
+/// Reads landmarks written by `write_landmarks`.
+pub fn read_landmarks<R: Read>(r: &mut R) -> io::Result<(LandmarkParams, Vec<Landmark>)> {
+    let mut magic = [0; 8];
+    r.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid("not a landmark file".to_string()));
+    }
+    let version = read_u32(r)?;
+    if version != VERSION {
+        return Err(invalid(format!(
+            "unsupported landmark file version {}",
+            version
+        )));
+    }
+    let count = read_u32(r)? as usize;
+    let params: LandmarkParams = read_params(r)?;
+    let landmarks = read_items(r, count, |r| {
+        let c = Complex::new(read_f64(r)?, read_f64(r)?);
+        let scale = read_f64(r)?;
+        let preperiod = read_u32(r)?;
+        let period = read_u32(r)?;
+        Ok(Landmark {
+            c,
+            preperiod,
+            period,
+            scale,
+        })
+    })?;
+    Ok((params, landmarks))
+}
+
+/// Saves `landmarks` to the file `fname`.
+pub fn save_landmarks(
+    params: &LandmarkParams,
+    landmarks: &[Landmark],
+    fname: String,
+) -> io::Result<()> {
+    let mut w = BufWriter::new(File::create(fname)?);
+    write_landmarks(&mut w, params, landmarks)?;
+    w.flush()
+}
+
+/// Loads the landmarks in the file `fname`.
+pub fn load_landmarks(fname: String) -> io::Result<(LandmarkParams, Vec<Landmark>)> {
+    let mut r = BufReader::new(File::open(fname)?);
+    read_landmarks(&mut r)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use buddha::MANDELBROT_EXPONENT;
+
+    fn params() -> LandmarkParams {
+        LandmarkParams {
+            function: FormulaMap::new(Formula::Mandelbrot, MANDELBROT_EXPONENT),
+            max_period: 4,
+            max_preperiod: 2,
+            re_min: -2.5,
+            re_max: 1.0,
+            im_min: -1.5,
+            im_max: 1.5,
+        }
+    }
+
+    fn find(landmarks: &[Landmark], c: Complex) -> Option<&Landmark> {
+        landmarks.iter().find(|l| (l.c - c).norm() < 1e-6)
+    }
+
+    #[test]
+    fn finds_centres_and_misiurewicz_points() {
+        let landmarks = find_landmarks(&params(), 2);
+        let count = |preperiod, period| {
+            landmarks
+                .iter()
+                .filter(|l| l.preperiod == preperiod && l.period == period)
+                .count()
+        };
+        // There are 1, 1, 3 and 6 centres of periods 1 to 4.
+        assert_eq!(
+            (count(0, 1), count(0, 2), count(0, 3), count(0, 4)),
+            (1, 1, 3, 6)
+        );
+        let centre = find(&landmarks, Complex::new(-1.754877666, 0.0)).unwrap();
+        assert_eq!((centre.preperiod, centre.period), (0, 3));
+        // -2 lands on the fixed point 2, and i on a cycle of length 2.
+        let tip = find(&landmarks, Complex::new(-2.0, 0.0)).unwrap();
+        assert_eq!((tip.preperiod, tip.period), (2, 1));
+        let i = find(&landmarks, Complex::new(0.0, 1.0)).unwrap();
+        assert_eq!((i.preperiod, i.period), (2, 2));
+        assert!(landmarks.iter().all(|l| l.scale > 0.0 && l.preperiod != 1));
+        // Nothing turns up twice.
+        for (n, a) in landmarks.iter().enumerate() {
+            assert!(landmarks[n + 1..].iter().all(|b| (a.c - b.c).norm() > 1e-6));
+        }
+    }
+
+    #[test]
+    fn landmarks_round_trip() {
+        let landmarks = find_landmarks(&params(), 1);
+        let mut buf = Vec::new();
+        write_landmarks(&mut buf, &params(), &landmarks).unwrap();
+        let (read_params, read) = read_landmarks(&mut &buf[..]).unwrap();
+        assert_eq!(read_params, params());
+        assert_eq!(read, landmarks);
+
+        buf[0] = b'P';
+        assert!(read_landmarks(&mut &buf[..]).is_err());
+    }
+}
@@ -18,5 +18,11 @@ extern crate serde_derive;
 
 
 pub mod buddha;
+pub mod histogram;
+pub mod importance;
+pub mod interior;
+pub mod landmarks;
 pub mod ppm;
+pub mod viewport;
+pub mod watchdog;
 
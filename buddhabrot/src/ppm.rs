@@ -2,7 +2,6 @@ extern crate image;
 extern crate regex;
 
 use std;
-use std::cmp::max;
 use std::f64::consts;
 use std::fs::File;
 use std::io::{Read, Write};
@@ -14,9 +13,9 @@ use self::regex::Regex;
 pub struct Img {
     height: i64,
     width: i64,
-    maximum: i64,
-    minimum: i64,
-    pixels: Vec<i64>,
+    maximum: f64,
+    minimum: f64,
+    pixels: Vec<f64>,
 }
 
 // This fexp scaling function is taken from here: https://www.brodie-tyrrell.org/bbrot/
@@ -29,45 +28,131 @@ pub fn log(x: f64, factor: f64) -> f64 {
 }
 
 /// The Img struct is the simplest possible implementation of an image: a two dimensional array of
-/// pixels, each pixel represented only as a single integer representing the brightness of that
-/// pixel. Multiple Img structs together can represent an RGB image, with one Img struct per
-/// channel.
+/// pixels, each pixel represented only as a single number representing the brightness of that
+/// pixel. Pixels usually count how many orbits passed through them, but weighted trajectories add
+/// fractional amounts, so they're kept as floating point values. Multiple Img structs together
+/// can represent an RGB image, with one Img struct per channel.
+///
+/// Pixels are stored in row-major order: `x` counts columns from the left edge of the image and
+/// `y` counts rows from the top, so the pixel at (x, y) lives at index `y * width + x`.
 impl Img {
-    pub fn new(h: i64, w: i64) -> Img {
+    pub fn new(width: i64, height: i64) -> Img {
         Img {
-            height: h,
-            width: w,
-            maximum: 1,
-            minimum: 1000,
-            pixels: vec![0; (h * w) as usize],
+            height,
+            width,
+            maximum: 1.0,
+            minimum: 1000.0,
+            pixels: vec![0.0; (height * width) as usize],
         }
     }
-    pub fn set_px(&mut self, x: i64, y: i64, val: i64) {
-        if x < self.width && x >= 0 {
-            if y < self.height && y >= 0 {
-                if val > self.maximum {
-                    self.maximum = val
-                }
-                if val < self.minimum {
-                    self.minimum = val
-                }
-                self.pixels[((self.height * y) + x) as usize] = val
+    /// Builds an image from raw pixel values in row-major order.
+    pub fn from_pixels(width: i64, height: i64, pixels: Vec<f64>) -> Img {
+        assert_eq!(
+            pixels.len(),
+            (width * height) as usize,
+            "a {}x{} image needs exactly {} pixels",
+            width,
+            height,
+            width * height
+        );
+        let mut img = Img::new(width, height);
+        img.pixels = pixels;
+        img.recalculate_extremes();
+        img
+    }
+    /// Adds every pixel of `other` onto the matching pixel of this image, so that separately
+    /// accumulated images of the same region can be combined. Panics if the dimensions differ.
+    pub fn add(&mut self, other: &Img) {
+        assert!(
+            self.width == other.width && self.height == other.height,
+            "cannot add a {}x{} image onto a {}x{} image",
+            other.width,
+            other.height,
+            self.width,
+            self.height
+        );
+        for (px, o) in self.pixels.iter_mut().zip(other.pixels.iter()) {
+            *px += *o;
+        }
+        self.recalculate_extremes();
+    }
+    fn recalculate_extremes(&mut self) {
+        self.maximum = 1.0;
+        self.minimum = 1000.0;
+        for &px in self.pixels.iter() {
+            if px > self.maximum {
+                self.maximum = px
+            }
+            if px < self.minimum {
+                self.minimum = px
             }
         }
     }
+    pub fn width(&self) -> i64 {
+        self.width
+    }
+    pub fn height(&self) -> i64 {
+        self.height
+    }
+    /// The brightest pixel value within the image.
+    pub fn maximum(&self) -> f64 {
+        self.maximum
+    }
+    /// The raw pixel values in row-major order.
+    pub fn pixels(&self) -> &[f64] {
+        &self.pixels
+    }
+    /// Whether (x, y) lies within the bounds of the image.
+    pub fn contains(&self, x: i64, y: i64) -> bool {
+        x < self.width && x >= 0 && y < self.height && y >= 0
+    }
+    /// The position of the pixel (x, y) within `pixels`. Panics if (x, y) is out of bounds.
+    pub fn index(&self, x: i64, y: i64) -> usize {
+        assert!(
+            self.contains(x, y),
+            "pixel ({}, {}) is outside of a {}x{} image",
+            x,
+            y,
+            self.width,
+            self.height
+        );
+        ((self.width * y) + x) as usize
+    }
+    /// Returns the value of the pixel (x, y), or None if (x, y) is out of bounds.
+    pub fn get_px(&self, x: i64, y: i64) -> Option<f64> {
+        if self.contains(x, y) {
+            return Some(self.pixels[self.index(x, y)]);
+        }
+        None
+    }
+    pub fn set_px(&mut self, x: i64, y: i64, val: f64) {
+        if self.contains(x, y) {
+            if val > self.maximum {
+                self.maximum = val
+            }
+            if val < self.minimum {
+                self.minimum = val
+            }
+            let idx = self.index(x, y);
+            self.pixels[idx] = val
+        }
+    }
     pub fn incr_px(&mut self, x: i64, y: i64) {
-        if x < self.width && x >= 0 {
-            if y < self.height && y >= 0 {
-                let mut px = self.pixels[((self.height * y) + x) as usize];
-                px = px + 1;
-                if px > self.maximum {
-                    self.maximum = px
-                }
-                if px < self.minimum {
-                    self.minimum = px
-                }
-                self.pixels[((self.height * y) + x) as usize] = px;
+        self.add_px(x, y, 1.0)
+    }
+    /// Adds `amount` to the pixel (x, y), such as the weight of a trajectory passing through it.
+    pub fn add_px(&mut self, x: i64, y: i64, amount: f64) {
+        if self.contains(x, y) {
+            let idx = self.index(x, y);
+            let mut px = self.pixels[idx];
+            px += amount;
+            if px > self.maximum {
+                self.maximum = px
             }
+            if px < self.minimum {
+                self.minimum = px
+            }
+            self.pixels[idx] = px;
         }
     }
     /// Returns the pixel specified scaled to a u8 by passing the raw value of the pixel and the
@@ -77,33 +162,67 @@ impl Img {
     where
         F: Fn(f64, f64) -> f64,
     {
-        let val = self.pixels[((self.height * y as i64) + x as i64) as usize] as f64;
-        (delegate(val, self.maximum as f64) * 255.0) as u8
+        let val = self.pixels[self.index(x, y)];
+        (delegate(val, self.maximum) * 255.0) as u8
     }
     pub fn scaled_pix_val(&self, x: i64, y: i64) -> u8 {
-        self.scaled_pix_delegate(x, y, |val, mx| {
-            fexp(val as f64, 0.001) / fexp(mx as f64, 0.001)
-        })
+        self.scaled_pix_delegate(x, y, |val, mx| fexp(val, 0.001) / fexp(mx, 0.001))
+    }
+}
+
+// check_dimensions panics unless every channel in imgs has the same width and height, since the
+// writers below walk all of the channels in lockstep.
+fn check_dimensions(imgs: &[Img]) {
+    for img in imgs.iter() {
+        assert!(
+            img.width == imgs[0].width && img.height == imgs[0].height,
+            "image channels have mismatched dimensions: {}x{} and {}x{}",
+            imgs[0].width,
+            imgs[0].height,
+            img.width,
+            img.height
+        );
     }
 }
 
-// write_ppm writes a PPM formated image from a vector of Img structs
-pub fn write_ppm(imgs: &Vec<Img>, fname: String) {
+// The largest maxval the PPM format allows.
+const PPM_MAXVAL: f64 = 65535.0;
+
+// The brightest pixel across all of the channels in imgs.
+fn brightest(imgs: &[Img]) -> f64 {
+    imgs[0].maximum.max(imgs[1].maximum).max(imgs[2].maximum)
+}
+
+// write_ppm writes a PPM formated image from a vector of Img structs. PPM can only hold whole
+// numbers up to 65535, so if any pixel is brighter than that or isn't a whole number, as happens
+// with weighted trajectories, then every pixel is scaled linearly to fill that range. Use
+// `histogram::save_histograms` to keep the raw values without any loss.
+pub fn write_ppm(imgs: &[Img], fname: String) {
+    check_dimensions(imgs);
     let mut ppm = std::io::BufWriter::new(File::create(fname.as_str()).unwrap());
+    let max_brightness = brightest(imgs);
+    let whole = imgs
+        .iter()
+        .all(|img| img.pixels.iter().all(|px| px.fract() == 0.0));
+    let unscaled = whole && max_brightness <= PPM_MAXVAL;
+    let maxval = if unscaled { max_brightness } else { PPM_MAXVAL };
+    let scale = |val: f64| {
+        if unscaled {
+            return val as i64;
+        }
+        ((val / max_brightness) * PPM_MAXVAL).round() as i64
+    };
 
     write!(ppm, "P3\n# Created by leland batey RustPPM\n").unwrap();
-    write!(ppm, "{} {}\n", imgs[0].width, imgs[0].height).unwrap();
-    write!(
-        ppm,
-        "{}\n",
-        max(imgs[0].maximum, max(imgs[1].maximum, imgs[2].maximum))
-    )
-    .unwrap();
+    writeln!(ppm, "{} {}", imgs[0].width, imgs[0].height).unwrap();
+    writeln!(ppm, "{}", maxval as i64).unwrap();
     for pidx in 0..imgs[0].pixels.len() {
-        write!(
+        writeln!(
             ppm,
-            "{} {} {}\n",
-            imgs[0].pixels[pidx], imgs[1].pixels[pidx], imgs[2].pixels[pidx]
+            "{} {} {}",
+            scale(imgs[0].pixels[pidx]),
+            scale(imgs[1].pixels[pidx]),
+            scale(imgs[2].pixels[pidx])
         )
         .unwrap();
     }
@@ -111,27 +230,29 @@ pub fn write_ppm(imgs: &Vec<Img>, fname: String) {
 
 // write_scaled_ppm writes a PPM formated image from a vector of Img structs, but with each pixel
 // value scaled in brightness using the `fexp` function.
-pub fn write_scaled_ppm(imgs: &Vec<Img>, fname: String) {
+pub fn write_scaled_ppm(imgs: &[Img], fname: String) {
+    check_dimensions(imgs);
     let mut ppm = std::io::BufWriter::new(File::create(fname.as_str()).unwrap());
-    let scale_fn = |val, mx| {
-        let scaled_val = ((fexp(val as f64, 0.050) / fexp(mx as f64, 0.050)) * 255.0) as u8;
+    let scale_fn = |val: f64, mx: f64| {
+        let scaled_val = ((fexp(val, 0.050) / fexp(mx, 0.050)) * 255.0) as u8;
         // If a pixel is below the minimum brightness threshold but does still have a brightness,
         // then scale that pixel to the minimum brightness threshold.
-        if val > 0 && val < (mx / 255) {
-            return (mx / 255 + 1 as i64) as u8;
+        let threshold = (mx / 255.0).floor();
+        if val > 0.0 && val < threshold {
+            (threshold + 1.0) as u8
         } else {
-            return scaled_val;
+            scaled_val
         }
     };
-    let max_brightness = max(imgs[0].maximum, max(imgs[1].maximum, imgs[2].maximum));
+    let max_brightness = brightest(imgs);
 
     write!(ppm, "P3\n# Created by leland batey RustPPM\n").unwrap();
-    write!(ppm, "{} {}\n", imgs[0].width, imgs[0].height).unwrap();
-    write!(ppm, "{}\n", scale_fn(max_brightness, max_brightness)).unwrap();
+    writeln!(ppm, "{} {}", imgs[0].width, imgs[0].height).unwrap();
+    writeln!(ppm, "{}", scale_fn(max_brightness, max_brightness)).unwrap();
     for pidx in 0..imgs[0].pixels.len() {
-        write!(
+        writeln!(
             ppm,
-            "{} {} {}\n",
+            "{} {} {}",
             scale_fn(imgs[0].pixels[pidx], max_brightness),
             scale_fn(imgs[1].pixels[pidx], max_brightness),
             scale_fn(imgs[2].pixels[pidx], max_brightness),
@@ -140,10 +261,11 @@ pub fn write_scaled_ppm(imgs: &Vec<Img>, fname: String) {
     }
 }
 
-pub fn write_scaled_png<F>(imgs: &Vec<Img>, fname: String, scale_func: F)
+pub fn write_scaled_png<F>(imgs: &[Img], fname: String, scale_func: F)
 where
     F: Fn(f64, f64) -> f64,
 {
+    check_dimensions(imgs);
     let mut imgbuf = image::ImageBuffer::<image::Rgb<u8>, Vec<u8>>::new(
         imgs[0].width as u32,
         imgs[0].height as u32,
@@ -158,7 +280,7 @@ where
     let _ = image::DynamicImage::ImageRgb8(imgbuf).save(fname);
 }
 
-pub fn write_png(imgs: &Vec<Img>, fname: String) {
+pub fn write_png(imgs: &[Img], fname: String) {
     write_scaled_png(imgs, fname, |val, _| val);
 }
 
@@ -170,122 +292,116 @@ pub fn read_ppm(fname: String) -> Vec<Img> {
     let re = Regex::new(r"#.*").unwrap();
     let nocomments = re.replace_all(contents.as_str(), "");
     let lines = nocomments.split('\n').collect::<Vec<&str>>();
-    // Simple state machine for parsing PPM
-    enum State {
-        AwaitMagicNum,
-        AwaitWidth,
-        AwaitHeight,
-        AwaitMaxval,
-        AwaitRed,
-        AwaitGreen,
-        AwaitBlue,
+    // Simple state machine for parsing PPM, where each state names the token we're waiting on
+    enum Await {
+        MagicNum,
+        Width,
+        Height,
+        Maxval,
+        Red,
+        Green,
+        Blue,
     }
     // Our vector of images, each representing a color channel, in order [r, g, b].
     let mut imgs: Vec<Img> = vec![];
-    let mut cur: State = State::AwaitMagicNum;
+    let mut cur: Await = Await::MagicNum;
     let mut height: i64 = 0;
     let mut width: i64 = 0;
 
     let mut x = 0;
     let mut y = 0;
     for line in lines {
-        if line == "" {
+        if line.is_empty() {
             continue;
         }
         let tokens = line.split(char::is_whitespace).collect::<Vec<&str>>();
         for token in tokens {
-            if token == "" {
+            if token.is_empty() {
                 continue;
             }
             match cur {
-                State::AwaitMagicNum => {
+                Await::MagicNum => {
                     if token == "P3" {
-                        cur = State::AwaitWidth;
+                        cur = Await::Width;
                     }
                 }
-                State::AwaitWidth => {
+                Await::Width => {
                     width = token.parse().unwrap();
-                    cur = State::AwaitHeight;
+                    cur = Await::Height;
                 }
-                State::AwaitHeight => {
+                Await::Height => {
                     height = token.parse().unwrap();
-                    cur = State::AwaitMaxval;
+                    cur = Await::Maxval;
                 }
-                State::AwaitMaxval => {
+                Await::Maxval => {
                     // We actually ignore the maxval and calculate that on a per-channel level
                     // automatically since each channel of RGB is represented as its own Img
                     // structure.
-                    cur = State::AwaitRed;
+                    cur = Await::Red;
                     // But, let's take the time now to initialize our images
                     imgs.push(Img::new(width, height));
                     imgs.push(Img::new(width, height));
                     imgs.push(Img::new(width, height));
                 }
-                State::AwaitRed => {
+                Await::Red => {
                     imgs[0].set_px(x, y, token.parse().unwrap());
-                    cur = State::AwaitGreen;
+                    cur = Await::Green;
                 }
-                State::AwaitGreen => {
+                Await::Green => {
                     imgs[1].set_px(x, y, token.parse().unwrap());
-                    cur = State::AwaitBlue;
+                    cur = Await::Blue;
                 }
-                State::AwaitBlue => {
+                Await::Blue => {
                     imgs[2].set_px(x, y, token.parse().unwrap());
-                    cur = State::AwaitRed;
+                    cur = Await::Red;
                     x += 1;
+                    if x == width {
+                        y += 1;
+                        x = 0;
+                    }
                 }
             }
-            if x == width {
-                y += 1;
-                x = 0;
-            }
         }
     }
-    return imgs;
+    imgs
 }
 
+type ScaleFn = Box<dyn Fn(f64, f64) -> f64>;
+
 // rescale_ppm accepts the path of a PPM file, reads that ppm file, applies several different
 // scaling functions to the values of each pixel in the PPM and saves a new PNG for each scaling
 // function.
-pub fn rescale_ppm(imgs: &Vec<Img>, ppmname: String) {
+pub fn rescale_ppm(imgs: &[Img], ppmname: String) {
     println!("{}", imgs.len());
-    println!("{}x{}", imgs[1].width, imgs[0].height);
-    let mut scaling_funcs: Vec<(&str, Box<dyn Fn(f64, f64) -> f64>)> = Vec::new();
+    check_dimensions(imgs);
+    println!("{}x{}", imgs[0].width, imgs[0].height);
+    let mut scaling_funcs: Vec<(&str, ScaleFn)> = Vec::new();
     scaling_funcs.push((
         "fexp0_001",
-        Box::new(|val, mx| (fexp(val as f64, 0.001) / fexp(mx as f64, 0.001))),
+        Box::new(|val, mx| fexp(val, 0.001) / fexp(mx, 0.001)),
     ));
     scaling_funcs.push((
         "fexp0_005",
-        Box::new(|val, mx| (fexp(val as f64, 0.005) / fexp(mx as f64, 0.005))),
+        Box::new(|val, mx| fexp(val, 0.005) / fexp(mx, 0.005)),
     ));
     scaling_funcs.push((
         "fexp0_010",
-        Box::new(|val, mx| (fexp(val as f64, 0.010) / fexp(mx as f64, 0.010))),
+        Box::new(|val, mx| fexp(val, 0.010) / fexp(mx, 0.010)),
     ));
     scaling_funcs.push((
         "fexp0_050",
-        Box::new(|val, mx| (fexp(val as f64, 0.050) / fexp(mx as f64, 0.050))),
+        Box::new(|val, mx| fexp(val, 0.050) / fexp(mx, 0.050)),
     ));
     scaling_funcs.push((
         "fexp0_100",
-        Box::new(|val, mx| (fexp(val as f64, 0.100) / fexp(mx as f64, 0.100))),
-    ));
-    scaling_funcs.push((
-        "log1_0",
-        Box::new(|val, mx| log(val as f64, 1.0) / log(mx as f64, 1.0)),
-    ));
-    scaling_funcs.push((
-        "log0_5",
-        Box::new(|val, mx| log(val as f64, 0.5) / log(mx as f64, 0.5)),
-    ));
-    scaling_funcs.push((
-        "log0_1",
-        Box::new(|val, mx| log(val as f64, 0.1) / log(mx as f64, 0.1)),
+        Box::new(|val, mx| fexp(val, 0.100) / fexp(mx, 0.100)),
     ));
+    scaling_funcs.push(("log1_0", Box::new(|val, mx| log(val, 1.0) / log(mx, 1.0))));
+    scaling_funcs.push(("log0_5", Box::new(|val, mx| log(val, 0.5) / log(mx, 0.5))));
+    scaling_funcs.push(("log0_1", Box::new(|val, mx| log(val, 0.1) / log(mx, 0.1))));
     scaling_funcs.push((
         "log0_01",
-        Box::new(|val, mx| log(val as f64, 0.01) / log(mx as f64, 0.01)),
+        Box::new(|val, mx| log(val, 0.01) / log(mx, 0.01)),
     ));
     scaling_funcs.push((
         "ceil",
@@ -293,7 +409,7 @@ pub fn rescale_ppm(imgs: &Vec<Img>, ppmname: String) {
             if val > 0.0 {
                 return 1.0;
             }
-            return 0.0;
+            0.0
         }),
     ));
 
@@ -313,3 +429,152 @@ pub fn rescale_ppm(imgs: &Vec<Img>, ppmname: String) {
         let _ = image::DynamicImage::ImageRgb8(imgbuf).save(pngname);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> String {
+        let mut path = std::env::temp_dir();
+        path.push(format!(
+            "buddhabrot-ppm-test-{}-{}",
+            std::process::id(),
+            name
+        ));
+        path.to_str().unwrap().to_string()
+    }
+
+    // Builds three channels where every pixel's value encodes its own coordinates, so any mixup
+    // of rows and columns shows up as a wrong value.
+    fn coordinate_imgs(width: i64, height: i64) -> Vec<Img> {
+        let mut imgs = vec![
+            Img::new(width, height),
+            Img::new(width, height),
+            Img::new(width, height),
+        ];
+        for y in 0..height {
+            for x in 0..width {
+                imgs[0].set_px(x, y, x as f64);
+                imgs[1].set_px(x, y, y as f64);
+                imgs[2].set_px(x, y, ((x + y) % 7) as f64);
+            }
+        }
+        imgs
+    }
+
+    #[test]
+    fn rectangular_layout_is_row_major() {
+        let mut img = Img::new(1920, 1080);
+        assert_eq!(img.width(), 1920);
+        assert_eq!(img.height(), 1080);
+        assert_eq!(img.pixels().len(), 1920 * 1080);
+
+        img.incr_px(1919, 1079);
+        img.incr_px(1919, 1079);
+        img.set_px(1000, 5, 7.0);
+        assert_eq!(img.get_px(1919, 1079), Some(2.0));
+        assert_eq!(img.get_px(1000, 5), Some(7.0));
+        assert_eq!(img.pixels()[1079 * 1920 + 1919], 2.0);
+        assert_eq!(img.pixels()[5 * 1920 + 1000], 7.0);
+        assert_eq!(img.maximum(), 7.0);
+
+        // Out of bounds writes are ignored rather than landing on some other pixel.
+        img.incr_px(1920, 0);
+        img.set_px(0, 1080, 99.0);
+        assert_eq!(img.get_px(1920, 0), None);
+        assert_eq!(img.pixels().iter().sum::<f64>(), 9.0);
+    }
+
+    #[test]
+    fn add_px_accumulates_weights() {
+        let mut img = Img::new(4, 3);
+        img.add_px(2, 1, 0.25);
+        img.add_px(2, 1, 2.5);
+        img.incr_px(2, 1);
+        img.add_px(4, 1, 8.0);
+        assert_eq!(img.get_px(2, 1), Some(3.75));
+        assert_eq!(img.maximum(), 3.75);
+        assert_eq!(img.pixels().iter().sum::<f64>(), 3.75);
+    }
+
+    #[test]
+    fn write_ppm_scales_fractional_images() {
+        let mut imgs = vec![Img::new(2, 1), Img::new(2, 1), Img::new(2, 1)];
+        imgs[0].set_px(0, 0, 2.5);
+        imgs[0].set_px(1, 0, 1.25);
+        let fname = temp_path("fractional.ppm");
+        write_ppm(&imgs, fname.clone());
+        let read = read_ppm(fname.clone());
+        std::fs::remove_file(&fname).unwrap();
+
+        assert_eq!(read[0].pixels(), &[65535.0, 32768.0]);
+        assert_eq!(read[1].pixels(), &[0.0, 0.0]);
+    }
+
+    #[test]
+    fn ppm_round_trip_preserves_rectangular_images() {
+        for &(width, height) in [(192, 108), (108, 192)].iter() {
+            let imgs = coordinate_imgs(width, height);
+            let fname = temp_path(&format!("{}x{}.ppm", width, height));
+            write_ppm(&imgs, fname.clone());
+            let read = read_ppm(fname.clone());
+            std::fs::remove_file(&fname).unwrap();
+
+            assert_eq!(read.len(), 3);
+            for (orig, img) in imgs.iter().zip(read.iter()) {
+                assert_eq!(img.width(), width);
+                assert_eq!(img.height(), height);
+                assert_eq!(img.pixels(), orig.pixels());
+            }
+        }
+    }
+
+    #[test]
+    fn png_writers_handle_rectangular_images() {
+        let imgs = coordinate_imgs(1920, 1080);
+        let fname = temp_path("1920x1080.png");
+        write_scaled_png(&imgs, fname.clone(), |val, mx| val / mx);
+        let png = image::open(&fname).unwrap().to_rgb8();
+        std::fs::remove_file(&fname).unwrap();
+
+        assert_eq!(png.dimensions(), (1920, 1080));
+        assert_eq!(png.get_pixel(1919, 0)[0], 255);
+        assert_eq!(png.get_pixel(0, 1079)[0], 0);
+        assert_eq!(
+            png.get_pixel(0, 1079)[1],
+            imgs[1].scaled_pix_delegate(0, 1079, |v, m| v / m)
+        );
+    }
+
+    #[test]
+    fn rescale_ppm_handles_rectangular_images() {
+        let imgs = coordinate_imgs(64, 36);
+        let prefix = temp_path("rescale-");
+        rescale_ppm(&imgs, prefix.clone());
+        let png = image::open(prefix.clone() + "ceil.png").unwrap().to_rgb8();
+        assert_eq!(png.dimensions(), (64, 36));
+        for name in [
+            "fexp0_001",
+            "fexp0_005",
+            "fexp0_010",
+            "fexp0_050",
+            "fexp0_100",
+            "log1_0",
+            "log0_5",
+            "log0_1",
+            "log0_01",
+            "ceil",
+        ]
+        .iter()
+        {
+            std::fs::remove_file(prefix.clone() + name + ".png").unwrap();
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn writers_reject_mismatched_channels() {
+        let imgs = vec![Img::new(4, 2), Img::new(4, 2), Img::new(2, 4)];
+        write_ppm(&imgs, temp_path("mismatched.ppm"));
+    }
+}
@@ -0,0 +1,183 @@
+use buddha::Complex;
+
+/// The height, in units of the complex plane, of the region framed by a viewport with a zoom of
+/// 1.0. This matches the framing trajectory-render has always used by default.
+pub const BASE_HEIGHT: f64 = 2.25;
+
+/// A Viewport is a (possibly rotated) rectangle of the complex plane which gets mapped onto an
+/// image. `zoom` is a linear magnification relative to `BASE_HEIGHT`, `aspect` is the width of
+/// the rectangle divided by its height, and `rotation` is the counter-clockwise angle, in
+/// radians, the rectangle is rotated by around its center.
+///
+/// Pixel coordinates grow to the right with the real part and downward with the imaginary part,
+/// with (0, 0) being the corner of the image nearest to the smallest real and imaginary parts
+/// when there's no rotation.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct Viewport {
+    pub center: Complex,
+    pub zoom: f64,
+    pub aspect: f64,
+    pub rotation: f64,
+}
+
+impl Default for Viewport {
+    fn default() -> Viewport {
+        Viewport::new(Complex::new(-0.375, 0.0), 1.0, 1.0, 0.0)
+    }
+}
+
+impl Viewport {
+    pub fn new(center: Complex, zoom: f64, aspect: f64, rotation: f64) -> Viewport {
+        Viewport {
+            center,
+            zoom,
+            aspect,
+            rotation,
+        }
+    }
+
+    /// Height of the framed region in units of the complex plane.
+    pub fn height(&self) -> f64 {
+        BASE_HEIGHT / self.zoom
+    }
+
+    /// Width of the framed region in units of the complex plane.
+    pub fn width(&self) -> f64 {
+        self.height() * self.aspect
+    }
+
+    // Returns the position of `z` relative to the frame, where (0.0, 0.0) is one corner of the
+    // frame and (1.0, 1.0) is the opposite corner.
+    fn unit_coords(&self, z: Complex) -> (f64, f64) {
+        let (sin, cos) = (-self.rotation).sin_cos();
+        let dx = z.re - self.center.re;
+        let dy = z.im - self.center.im;
+        let rx = dx * cos - dy * sin;
+        let ry = dx * sin + dy * cos;
+        (rx / self.width() + 0.5, ry / self.height() + 0.5)
+    }
+
+    fn point_at_unit(&self, u: f64, v: f64) -> Complex {
+        let (sin, cos) = self.rotation.sin_cos();
+        let rx = (u - 0.5) * self.width();
+        let ry = (v - 0.5) * self.height();
+        Complex::new(
+            self.center.re + rx * cos - ry * sin,
+            self.center.im + rx * sin + ry * cos,
+        )
+    }
+
+    /// Reports whether `z` falls inside the framed region.
+    pub fn contains(&self, z: Complex) -> bool {
+        let (u, v) = self.unit_coords(z);
+        (0.0..1.0).contains(&u) && (0.0..1.0).contains(&v)
+    }
+
+    /// Returns the pixel of a `width` x `height` image which `z` lands in, or None if `z` lands
+    /// outside of the image.
+    pub fn to_pixel(&self, z: Complex, width: i64, height: i64) -> Option<(i64, i64)> {
+        let (u, v) = self.unit_coords(z);
+        let xp = u * width as f64;
+        let yp = v * height as f64;
+        if xp < 0.0 || xp >= (width as f64) || yp < 0.0 || yp >= (height as f64) {
+            return None;
+        }
+        Some((xp as i64, yp as i64))
+    }
+
+    /// Returns the point of the complex plane at the pixel coordinates (`x`, `y`) of a `width` x
+    /// `height` image. Coordinates are continuous, so the center of pixel (0, 0) is (0.5, 0.5).
+    pub fn to_complex(&self, x: f64, y: f64, width: i64, height: i64) -> Complex {
+        self.point_at_unit(x / width as f64, y / height as f64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts;
+
+    const WIDTH: i64 = 192;
+    const HEIGHT: i64 = 108;
+
+    fn viewports() -> Vec<Viewport> {
+        vec![
+            Viewport::default(),
+            Viewport::new(Complex::new(-0.5, 0.25), 3.0, 16.0 / 9.0, 0.0),
+            Viewport::new(Complex::new(0.1, -0.6), 40.0, 1.0, consts::PI / 6.0),
+            Viewport::new(Complex::new(-1.25, 0.1), 0.5, 9.0 / 16.0, -2.0),
+        ]
+    }
+
+    fn assert_close(a: Complex, b: Complex) {
+        assert!((a - b).norm() < 1e-12, "{:?} is not {:?}", a, b);
+    }
+
+    #[test]
+    fn pixel_centers_round_trip() {
+        for view in viewports() {
+            for &(x, y) in [
+                (0, 0),
+                (WIDTH - 1, 0),
+                (0, HEIGHT - 1),
+                (17, 93),
+                (191, 107),
+            ]
+            .iter()
+            {
+                let z = view.to_complex(x as f64 + 0.5, y as f64 + 0.5, WIDTH, HEIGHT);
+                assert!(view.contains(z));
+                assert_eq!(view.to_pixel(z, WIDTH, HEIGHT), Some((x, y)));
+            }
+        }
+    }
+
+    #[test]
+    fn frames_non_square_regions() {
+        let view = Viewport::new(Complex::new(-0.5, 0.25), 2.0, 2.0, 0.0);
+        assert_eq!(view.width(), 2.0 * view.height());
+        let (w, h) = (view.width(), view.height());
+        assert_close(
+            view.to_complex(0.0, 0.0, WIDTH, HEIGHT),
+            Complex::new(-0.5 - w / 2.0, 0.25 - h / 2.0),
+        );
+        assert_close(
+            view.to_complex(WIDTH as f64, HEIGHT as f64, WIDTH, HEIGHT),
+            Complex::new(-0.5 + w / 2.0, 0.25 + h / 2.0),
+        );
+    }
+
+    #[test]
+    fn rotation_turns_the_frame_counter_clockwise() {
+        let center = Complex::new(-0.5, 0.25);
+        let view = Viewport::new(center, 1.0, 16.0 / 9.0, consts::PI / 2.0);
+        // The middle of the right edge of the image is turned to straight above the center.
+        let right = view.to_complex(WIDTH as f64, HEIGHT as f64 / 2.0, WIDTH, HEIGHT);
+        assert_close(
+            right,
+            Complex::new(center.re, center.im + view.width() / 2.0),
+        );
+        // So a point just to the right of the center is no longer in the frame.
+        let beside = Complex::new(center.re + view.height() / 2.0 + 0.01, center.im);
+        assert!(!view.contains(beside));
+        assert_eq!(view.to_pixel(beside, WIDTH, HEIGHT), None);
+    }
+
+    #[test]
+    fn points_outside_the_frame_have_no_pixel() {
+        for view in viewports() {
+            let outside = [
+                (-0.5, HEIGHT as f64 / 2.0),
+                (WIDTH as f64 + 0.5, HEIGHT as f64 / 2.0),
+                (WIDTH as f64 / 2.0, -0.5),
+                (WIDTH as f64 / 2.0, HEIGHT as f64 + 0.5),
+                (-3.0 * WIDTH as f64, 5.0 * HEIGHT as f64),
+            ];
+            for &(x, y) in outside.iter() {
+                let z = view.to_complex(x, y, WIDTH, HEIGHT);
+                assert!(!view.contains(z));
+                assert_eq!(view.to_pixel(z, WIDTH, HEIGHT), None);
+            }
+        }
+    }
+}
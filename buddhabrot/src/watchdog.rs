@@ -0,0 +1,93 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// A Watchdog watches some long running piece of work from its own thread, and complains on
+/// stderr each time `timeout` passes without any progress being reported through a `Ticker`. It
+/// never stops the work it's watching; knowing when work is finished is left to join handles and
+/// channel disconnection. The watchdog thread is stopped and joined when the Watchdog is dropped.
+pub struct Watchdog {
+    progress: Arc<AtomicUsize>,
+    done: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+/// Reports progress to a Watchdog. Tickers may be cloned and sent to other threads.
+#[derive(Clone)]
+pub struct Ticker {
+    progress: Arc<AtomicUsize>,
+}
+
+impl Ticker {
+    pub fn tick(&self) {
+        self.progress.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl Watchdog {
+    /// Starts watching. `what` describes the units of progress being made, e.g. "trajectories
+    /// found". A `timeout` of None creates a watchdog which never complains.
+    pub fn start(what: &str, timeout: Option<Duration>) -> Watchdog {
+        let progress = Arc::new(AtomicUsize::new(0));
+        let timeout = match timeout {
+            Some(t) => t,
+            None => {
+                return Watchdog {
+                    progress,
+                    done: None,
+                    thread: None,
+                }
+            }
+        };
+        let (done, stopped) = channel::<()>();
+        let watched = progress.clone();
+        let what = what.to_string();
+        let thread = thread::spawn(move || {
+            let mut last = 0;
+            let mut stalled_for = Duration::from_secs(0);
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(timeout) {
+                let current = watched.load(Ordering::Relaxed);
+                if current == last {
+                    stalled_for += timeout;
+                    eprintln!(
+                        "Watchdog: no progress in the last {:.1}s ({} {} so far)",
+                        stalled_for.as_secs_f64(),
+                        current,
+                        what
+                    );
+                } else {
+                    stalled_for = Duration::from_secs(0);
+                }
+                last = current;
+            }
+        });
+        Watchdog {
+            progress,
+            done: Some(done),
+            thread: Some(thread),
+        }
+    }
+
+    pub fn ticker(&self) -> Ticker {
+        Ticker {
+            progress: self.progress.clone(),
+        }
+    }
+
+    /// The progress reported by every ticker so far.
+    pub fn progress(&self) -> usize {
+        self.progress.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        // Hanging up the channel wakes the watchdog thread immediately.
+        drop(self.done.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
@@ -0,0 +1,217 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::thread;
+
+use buddhabrot::buddha::{Complex, IterationFunction};
+use rand::Rng;
+
+use sampler::Region;
+
+/// The cells of a grid over the sampling region which straddle the boundary of the set, found by
+/// iterating the corners of every cell: a cell is on the boundary when some of its corners escape
+/// and others don't. Every boundary cell is split into four for the next refinement level, down to
+/// `depth` levels below the starting grid, so each level follows the boundary more closely than
+/// the one before it.
+pub struct BoundaryCells {
+    region: Region,
+    grid: usize,
+    // The boundary cells of each level, as (x, y) cell coordinates within that level. Level `l`
+    // has `grid << l` cells along each side.
+    levels: Vec<Vec<(u32, u32)>>,
+    lookup: Vec<HashSet<(u32, u32)>>,
+}
+
+impl BoundaryCells {
+    /// Finds the boundary cells of a `grid` x `grid` grid over `region` and of `depth` levels
+    /// of refinement below it. Points are iterated with `function` for up to `max_iterations`
+    /// iterations, as `c` values, or as starting `z` values when `julia` holds the constant of a
+    /// Julia set.
+    pub fn build<F: IterationFunction + Sync>(
+        function: &F,
+        julia: Option<Complex>,
+        max_iterations: i64,
+        region: Region,
+        grid: usize,
+        depth: usize,
+        thread_count: usize,
+    ) -> BoundaryCells {
+        // Corners are kept in the coordinates of the finest level's lattice, so that corners
+        // shared between levels are only iterated once.
+        let side = (grid << depth) as f64;
+        let escapes = |(x, y): (u32, u32)| {
+            let point = region.at(x as f64 / side, y as f64 / side);
+            let (z, c) = match julia {
+                Some(c) => (point, c),
+                None => (Complex::new(0.0, 0.0), point),
+            };
+            if julia.is_none() && function.known_interior(c) {
+                return false;
+            }
+            let mut orbit = c
+                .orbit()
+                .with_function(function)
+                .starting_from(z)
+                .max_iterations(max_iterations);
+            orbit.by_ref().count();
+            orbit.escaped()
+        };
+        let mut corners: HashMap<(u32, u32), bool> = HashMap::new();
+        let mut levels: Vec<Vec<(u32, u32)>> = Vec::with_capacity(depth + 1);
+        for level in 0..=depth {
+            let candidates: Vec<(u32, u32)> = if level == 0 {
+                (0..grid as u32)
+                    .flat_map(|y| (0..grid as u32).map(move |x| (x, y)))
+                    .collect()
+            } else {
+                levels[level - 1]
+                    .iter()
+                    .flat_map(|&(x, y)| {
+                        [(0, 0), (1, 0), (0, 1), (1, 1)]
+                            .iter()
+                            .map(move |&(dx, dy)| (2 * x + dx, 2 * y + dy))
+                    })
+                    .collect()
+            };
+            let shift = depth - level;
+            let corners_of = |(x, y): (u32, u32)| {
+                [(0, 0), (1, 0), (0, 1), (1, 1)]
+                    .iter()
+                    .map(move |&(dx, dy)| ((x + dx) << shift, (y + dy) << shift))
+            };
+            let missing: BTreeSet<(u32, u32)> = candidates
+                .iter()
+                .flat_map(|&cell| corners_of(cell))
+                .filter(|corner| !corners.contains_key(corner))
+                .collect();
+            let missing: Vec<(u32, u32)> = missing.into_iter().collect();
+            let per_thread = missing.len().div_ceil(thread_count.max(1)).max(1);
+            let escaped: Vec<Vec<bool>> = thread::scope(|scope| {
+                let threads: Vec<_> = missing
+                    .chunks(per_thread)
+                    .map(|chunk| scope.spawn(move || chunk.iter().map(|&p| escapes(p)).collect()))
+                    .collect();
+                threads
+                    .into_iter()
+                    .map(|t| t.join().expect("a boundary thread panicked"))
+                    .collect()
+            });
+            corners.extend(missing.into_iter().zip(escaped.into_iter().flatten()));
+            levels.push(
+                candidates
+                    .into_iter()
+                    .filter(|&cell| {
+                        let mut outcomes = corners_of(cell).map(|corner| corners[&corner]);
+                        let first = outcomes.next();
+                        outcomes.any(|outcome| Some(outcome) != first)
+                    })
+                    .collect(),
+            );
+        }
+        let lookup = levels
+            .iter()
+            .map(|cells| cells.iter().cloned().collect())
+            .collect();
+        BoundaryCells {
+            region,
+            grid,
+            levels,
+            lookup,
+        }
+    }
+
+    /// The number of refinement levels, counting the starting grid.
+    pub fn level_count(&self) -> usize {
+        self.levels.len()
+    }
+
+    /// The number of boundary cells found at `level`.
+    pub fn cell_count(&self, level: usize) -> usize {
+        self.levels[level].len()
+    }
+
+    /// Whether any boundary cells were found at all.
+    pub fn is_empty(&self) -> bool {
+        self.levels.iter().all(|cells| cells.is_empty())
+    }
+
+    /// Draws a point from the boundary cells. Every level with boundary cells gets an equal
+    /// share of the points, spread uniformly over its cells, so the finer levels pack their
+    /// points more tightly around the boundary. Returns the point, the level it was drawn from,
+    /// and its weight: the probability density of drawing it uniformly from the region divided
+    /// by the probability density of drawing it here.
+    pub fn sample<R: Rng>(&self, rng: &mut R) -> (Complex, usize, f64) {
+        let nonempty: Vec<usize> = (0..self.levels.len())
+            .filter(|&l| !self.levels[l].is_empty())
+            .collect();
+        let level = nonempty[rng.gen_range(0, nonempty.len())];
+        let cells = &self.levels[level];
+        let (x, y) = cells[rng.gen_range(0, cells.len())];
+        let (rx, ry) = (rng.gen::<f64>(), rng.gen::<f64>());
+        let side = (self.grid << level) as f64;
+        let point = self
+            .region
+            .at((x as f64 + rx) / side, (y as f64 + ry) / side);
+
+        // The density, relative to the uniform density over the region, sums the share of every
+        // level which has a cell containing the point.
+        let mut density = 0.0;
+        for &other in &nonempty {
+            let cell = if other <= level {
+                let shift = level - other;
+                (x >> shift, y >> shift)
+            } else {
+                let scale = 1u32 << (other - level);
+                (
+                    (x << (other - level)) + (rx * scale as f64) as u32,
+                    (y << (other - level)) + (ry * scale as f64) as u32,
+                )
+            };
+            if self.lookup[other].contains(&cell) {
+                let cell_area = 1.0 / ((self.grid << other) as f64).powi(2);
+                density +=
+                    1.0 / (nonempty.len() as f64 * self.levels[other].len() as f64 * cell_area);
+            }
+        }
+        (point, level, 1.0 / density)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use buddhabrot::buddha::{unit_rng, Formula, FormulaMap, MANDELBROT_EXPONENT};
+
+    #[test]
+    fn weights_integrate_to_the_area_of_the_cells() {
+        let function = FormulaMap::new(Formula::Mandelbrot, MANDELBROT_EXPONENT);
+        let region = Region::default();
+        let cells = BoundaryCells::build(&function, None, 200, region, 8, 3, 2);
+        let finest = cells.level_count() - 1;
+        let side = (8 << finest) as f64;
+        let mut rng = unit_rng(5, 0);
+        let samples = 200_000;
+        // Each weighted sample estimates a share of the region: all of them together the share
+        // covered by the starting grid's boundary cells, and those in the finest level's cells
+        // the share covered by that level.
+        let (mut total, mut in_finest) = (0.0, 0.0);
+        for _ in 0..samples {
+            let (point, _, weight) = cells.sample(&mut rng);
+            total += weight;
+            let x = ((point.re - region.startx) / region.xspan() * side) as u32;
+            let y = ((point.im - region.starty) / region.yspan() * side) as u32;
+            if cells.lookup[finest].contains(&(x, y)) {
+                in_finest += weight;
+            }
+        }
+        for &(level, estimate) in &[(0, total), (finest, in_finest)] {
+            let share = cells.cell_count(level) as f64 / ((8 << level) as f64).powi(2);
+            let estimate = estimate / samples as f64;
+            assert!(
+                (estimate / share - 1.0).abs() < 0.02,
+                "level {}: {} against {}",
+                level,
+                estimate,
+                share
+            );
+        }
+    }
+}
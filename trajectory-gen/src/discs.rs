@@ -0,0 +1,135 @@
+use std::f64::consts;
+
+use buddhabrot::buddha::Complex;
+use buddhabrot::landmarks::Landmark;
+use rand::Rng;
+
+use sampler::Region;
+
+/// The discs around landmarks which the landmark sampler draws candidates from, one around each
+/// landmark with a radius of `radius` times its scale. Weighting a point means finding every disc
+/// holding it, so the discs are indexed by a grid over the sampling region, with each cell listing
+/// the discs which overlap it.
+pub struct LandmarkDiscs {
+    landmarks: Vec<Landmark>,
+    radius: f64,
+    region: Region,
+    grid: usize,
+    // The discs overlapping each cell of the grid, row by row, as indices into `landmarks`.
+    // Points outside of the region belong to the nearest cell, so the cells along the edges of
+    // the grid also list the discs reaching past that edge.
+    cells: Vec<Vec<u32>>,
+}
+
+impl LandmarkDiscs {
+    /// Indexes the discs around `landmarks` over `region`.
+    pub fn new(landmarks: Vec<Landmark>, radius: f64, region: Region) -> LandmarkDiscs {
+        // About one cell for every landmark: most discs are far smaller than a cell, so they're
+        // only listed in the few cells around their landmark.
+        let grid = ((landmarks.len() as f64).sqrt().ceil() as usize).clamp(1, 1024);
+        let mut cells = vec![vec![]; grid * grid];
+        for (i, landmark) in landmarks.iter().enumerate() {
+            let r = radius * landmark.scale;
+            let (x0, y0) = cell(&region, grid, landmark.c - Complex::new(r, r));
+            let (x1, y1) = cell(&region, grid, landmark.c + Complex::new(r, r));
+            for y in y0..=y1 {
+                for x in x0..=x1 {
+                    cells[y * grid + x].push(i as u32);
+                }
+            }
+        }
+        LandmarkDiscs {
+            landmarks,
+            radius,
+            region,
+            grid,
+            cells,
+        }
+    }
+
+    /// Draws a point from the discs: a landmark is picked uniformly, and the point is drawn
+    /// uniformly from the disc around it. Returns the point and its weight: the probability
+    /// density of drawing it uniformly from the region divided by the probability density of
+    /// drawing it here.
+    pub fn sample<R: Rng>(&self, rng: &mut R) -> (Complex, f64) {
+        let landmark = self.landmarks[rng.gen_range(0, self.landmarks.len())];
+        let r = self.radius * landmark.scale * rng.gen::<f64>().sqrt();
+        let angle = rng.gen_range(0.0, 2.0 * consts::PI);
+        let point = landmark.c + Complex::new(r * angle.cos(), r * angle.sin());
+        let area = self.region.xspan() * self.region.yspan();
+        (point, 1.0 / (area * self.density(point)))
+    }
+
+    // The probability density of drawing `point`. The discs may overlap, so the density sums the
+    // share of every disc holding the point.
+    fn density(&self, point: Complex) -> f64 {
+        let (x, y) = cell(&self.region, self.grid, point);
+        let mut density = 0.0;
+        for &i in &self.cells[y * self.grid + x] {
+            let landmark = self.landmarks[i as usize];
+            let radius = self.radius * landmark.scale;
+            if (point - landmark.c).norm() < radius {
+                density += 1.0 / (consts::PI * radius * radius);
+            }
+        }
+        density / self.landmarks.len() as f64
+    }
+}
+
+// The cell of a `grid` x `grid` grid over `region` which holds `point`, or the nearest cell to
+// it when it's outside of the region.
+fn cell(region: &Region, grid: usize, point: Complex) -> (usize, usize) {
+    let index = |t: f64| ((t * grid as f64).floor().max(0.0) as usize).min(grid - 1);
+    (
+        index((point.re - region.startx) / region.xspan()),
+        index((point.im - region.starty) / region.yspan()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use buddhabrot::buddha::unit_rng;
+
+    #[test]
+    fn indexed_density_counts_every_disc() {
+        let mut rng = unit_rng(11, 0);
+        let region = Region::default();
+        // Mostly small discs, with a few reaching across much of the region and past its edges.
+        let landmarks: Vec<Landmark> = (0..500)
+            .map(|i| Landmark {
+                c: region.sample(&mut rng),
+                preperiod: 0,
+                period: 1,
+                scale: if i % 100 == 0 {
+                    1.5
+                } else {
+                    rng.gen::<f64>() * 0.05
+                },
+            })
+            .collect();
+        let discs = LandmarkDiscs::new(landmarks.clone(), 1.0, region);
+        let wider = Region {
+            startx: region.startx - 1.0,
+            stopx: region.stopx + 1.0,
+            starty: region.starty - 1.0,
+            stopy: region.stopy + 1.0,
+        };
+        let mut holding = 0;
+        for _ in 0..10000 {
+            let point = wider.sample(&mut rng);
+            let mut density = 0.0;
+            for landmark in &landmarks {
+                if (point - landmark.c).norm() < landmark.scale {
+                    density += 1.0 / (consts::PI * landmark.scale * landmark.scale);
+                }
+            }
+            density /= landmarks.len() as f64;
+            assert_eq!(discs.density(point), density, "{:?}", point);
+            if density > 0.0 {
+                holding += 1;
+            }
+        }
+        assert!(holding > 1000, "{}", holding);
+    }
+}
@@ -6,20 +6,256 @@ extern crate time;
 
 extern crate buddhabrot;
 
-use std::collections::HashMap;
-use std::sync::mpsc::{channel, Sender};
+mod boundary;
+mod discs;
+mod quota;
+mod sampler;
+
+use std::cmp::min;
+use std::collections::BTreeMap;
+use std::f64::consts;
+use std::fmt;
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+use argparse::{ArgumentParser, Store, StoreOption, StoreTrue};
+use buddhabrot::buddha::{
+    replay, unit_rng, Complex, Formula, FormulaMap, IterationFunction, OrbitKind, Trajectory,
+    TrajectoryHeader, DEFAULT_CYCLE_EPSILON, MANDELBROT_EXPONENT,
+};
+use buddhabrot::importance::{
+    load_importance_map, save_importance_map, ImportanceMap, ImportanceParams,
+};
+use buddhabrot::interior::{load_mask, save_mask, InteriorMask, MaskParams, Masked};
+use buddhabrot::landmarks::{
+    self, find_landmarks, load_landmarks, save_landmarks, Landmark, LandmarkParams,
+};
+use buddhabrot::viewport::Viewport;
+use buddhabrot::watchdog::{Ticker, Watchdog};
+use rand::{ChaChaRng, Rng};
+
+use boundary::BoundaryCells;
+use discs::LandmarkDiscs;
+use quota::Quotas;
+use sampler::{
+    Boundary, Explore, ExploreConf, Halton, Importance, Landmarks, Metropolis, Region, Sampler,
+    SamplerKind, Sobol, Stratified, Uniform,
+};
+
+/// SearchConf holds the options which control how each thread searches for trajectories.
+#[derive(Clone)]
+struct SearchConf {
+    max_iterations: i64,
+    min_iterations: i64,
+    sampler: SamplerKind,
+    large_step_prob: f64,
+    region: Region,
+    viewport: Viewport,
+    // When greater than zero, only trajectories with at least this many points inside `viewport`
+    // are accepted.
+    min_view_hits: i64,
+    orbits: Orbits,
+    // Candidates are iterated with `formula`, raised to `exponent`.
+    formula: Formula,
+    exponent: f64,
+    // When set, the Julia set with this constant `c` is searched, and candidates are the starting
+    // `z` of each orbit rather than `c`.
+    julia: Option<Complex>,
+    // Orbits which come back to within this distance of an earlier point count as cycling.
+    cycle_epsilon: f64,
+    // When set, candidates whose distance estimate after `distance_iterations` iterations is
+    // beyond `distance_threshold` are rejected without iterating them fully.
+    distance_filter: bool,
+    distance_iterations: i64,
+    // The map the importance sampler draws candidates from.
+    importance: Option<Arc<ImportanceMap>>,
+    // The number of cells along each side of the stratified sampler's grid.
+    strata: usize,
+    // The boundary cells the boundary sampler draws candidates from.
+    boundary: Option<Arc<BoundaryCells>>,
+    // The discs around landmarks which the landmark sampler draws candidates from, and the
+    // radius of each disc, as a multiple of its landmark's scale.
+    landmarks: Option<Arc<LandmarkDiscs>>,
+    landmark_radius: f64,
+    // When set, the neighbourhoods of earlier finds are explored as well.
+    explore: Option<ExploreConf>,
+    // When set, trajectories are only kept while the bucket of their length has room, and the
+    // search runs until every bucket is full or `quota_budget` candidates have been tried. Some
+    // buckets may never fill, so the budget is always set along with the quotas.
+    quotas: Option<Arc<Quotas>>,
+    quota_budget: u64,
+    // When set, the search stops once this much time has passed, once its candidates have been
+    // iterated this many times in all, or once this much time passes without finding anything.
+    time_budget: Option<Duration>,
+    iteration_budget: Option<u64>,
+    stall_stop: Option<Duration>,
+}
+
+impl Default for SearchConf {
+    fn default() -> SearchConf {
+        SearchConf {
+            max_iterations: 1024,
+            min_iterations: 64,
+            sampler: SamplerKind::Uniform,
+            large_step_prob: 0.1,
+            region: Region::default(),
+            // The same framing trajectory-render uses by default.
+            viewport: Viewport::default(),
+            min_view_hits: 0,
+            orbits: Orbits::Escaping,
+            formula: Formula::Mandelbrot,
+            exponent: MANDELBROT_EXPONENT,
+            julia: None,
+            cycle_epsilon: DEFAULT_CYCLE_EPSILON,
+            distance_filter: false,
+            distance_iterations: 64,
+            importance: None,
+            strata: 64,
+            boundary: None,
+            landmarks: None,
+            landmark_radius: 1.0,
+            explore: None,
+            quotas: None,
+            quota_budget: 0,
+            time_budget: None,
+            iteration_budget: None,
+            stall_stop: None,
+        }
+    }
+}
 
-use argparse::{ArgumentParser, Store};
-use buddhabrot::buddha::{Complex, Trajectory};
-use rand::Rng;
+/// Counts of what happened to the candidates tried by a search.
+#[derive(Clone, Debug, Default)]
+struct SearchStats {
+    candidates: u64,
+    // The iterations of every candidate's orbit, not counting those of distance estimates.
+    iterations: u64,
+    distance_rejected: u64,
+    // For samplers which draw from refinement levels, the candidates drawn from each level and
+    // the trajectories accepted from them.
+    level_candidates: Vec<u64>,
+    level_accepted: Vec<u64>,
+    // Candidates drawn around earlier finds, the trajectories accepted from them, and the
+    // trajectories dropped as duplicates.
+    explored_candidates: u64,
+    explored_accepted: u64,
+    duplicates: u64,
+}
+
+impl SearchStats {
+    fn add(&mut self, other: SearchStats) {
+        self.candidates += other.candidates;
+        self.iterations += other.iterations;
+        self.distance_rejected += other.distance_rejected;
+        add_counts(&mut self.level_candidates, &other.level_candidates);
+        add_counts(&mut self.level_accepted, &other.level_accepted);
+        self.explored_candidates += other.explored_candidates;
+        self.explored_accepted += other.explored_accepted;
+        self.duplicates += other.duplicates;
+    }
+}
+
+fn add_counts(counts: &mut Vec<u64>, other: &[u64]) {
+    if counts.len() < other.len() {
+        counts.resize(other.len(), 0);
+    }
+    for (count, &n) in counts.iter_mut().zip(other) {
+        *count += n;
+    }
+}
+
+// Adds one to the count at `index`, growing `counts` as needed.
+fn count_at(counts: &mut Vec<u64>, index: usize) {
+    if counts.len() <= index {
+        counts.resize(index + 1, 0);
+    }
+    counts[index] += 1;
+}
+
+/// The distance from the set beyond which a point can't escape slowly enough to last
+/// `min_iterations` iterations: (pi / n)^2 for n iterations. The slowest escapes of z^2 + c are
+/// near the cusp of the main cardioid, where a point at a distance of r escapes after roughly
+/// pi / sqrt(r) iterations, so no orbit lasting n iterations starts further out than that.
+/// Sampling the plane never found one whose distance estimate was above 2.2 / n^2, which leaves
+/// the cutoff a margin of more than four times for the estimate's error. Other exponents have
+/// differently shaped cusps, so the cutoff only holds for an exponent of 2.
+fn distance_threshold(min_iterations: i64) -> f64 {
+    (consts::PI / min_iterations.max(1) as f64).powi(2)
+}
+
+/// Which kinds of orbit the search collects.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Orbits {
+    Escaping,
+    Bounded,
+    Both,
+}
+
+impl Orbits {
+    fn includes(self, kind: OrbitKind) -> bool {
+        match self {
+            Orbits::Escaping => kind == OrbitKind::Escaping,
+            Orbits::Bounded => kind == OrbitKind::Bounded,
+            Orbits::Both => true,
+        }
+    }
+}
+
+impl FromStr for Orbits {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Orbits, String> {
+        match s {
+            "escaping" => Ok(Orbits::Escaping),
+            "bounded" | "anti" => Ok(Orbits::Bounded),
+            "both" => Ok(Orbits::Both),
+            _ => Err(format!("unknown kind of orbit '{}'", s)),
+        }
+    }
+}
 
 fn main() {
     let mut thread_count = 3;
-    let mut trajectory_count = 1000;
-    let mut max_iterations: i64 = 1024;
-    let mut min_iterations: i64 = 64;
+    let mut trajectory_count: usize = 1000;
+    let mut view_rotate_degrees: f64 = 0.0;
+    let mut stall_seconds: f64 = 60.0;
+    let mut time_budget_seconds: Option<f64> = None;
+    let mut stall_stop_seconds: Option<f64> = None;
+    let mut seed: Option<u64> = None;
+    let mut unit_size: usize = 64;
+    let mut chain_length: Option<usize> = None;
+    let mut conf = SearchConf::default();
+    let mut julia_re: Option<f64> = None;
+    let mut julia_im: Option<f64> = None;
+    let mut build_mask = false;
+    let mut mask_size: usize = 256;
+    let mut save_mask_file: Option<String> = None;
+    let mut load_mask_file: Option<String> = None;
+    let mut explore = false;
+    let mut explore_conf = ExploreConf {
+        samples: 16,
+        share: 0.5,
+        radius: 0.001,
+        shrink: 0.7,
+        dedup: 1e-10,
+    };
+    let mut boundary_grid: usize = 64;
+    let mut boundary_depth: usize = 3;
+    let mut quota_buckets: usize = 0;
+    let mut bucket_quota: Option<usize> = None;
+    let mut quota_budget: Option<u64> = None;
+    let mut landmark_period: u32 = 8;
+    let mut landmark_preperiod: u32 = 3;
+    let mut save_landmarks_file: Option<String> = None;
+    let mut load_landmarks_file: Option<String> = None;
+    let mut importance_size: usize = 128;
+    let mut importance_samples: usize = 4;
+    let mut save_importance_file: Option<String> = None;
+    let mut load_importance_file: Option<String> = None;
     {
         let mut argparse = ArgumentParser::new();
         argparse.refer(&mut thread_count).add_option(
@@ -32,158 +268,1338 @@ fn main() {
             Store,
             "Absolute number of trajectories to find",
         );
-        argparse.refer(&mut max_iterations).add_option(
+        argparse.refer(&mut quota_buckets).add_option(
+            &["--quota-buckets"],
+            Store,
+            "Split the orbit lengths between --min-iters and --max-iters into this many \
+             log-spaced buckets, and stop keeping trajectories for a bucket once it's full. The \
+             search runs until every bucket is full or --quota-budget is spent (default 0, for no \
+             quotas)",
+        );
+        argparse.refer(&mut bucket_quota).add_option(
+            &["--bucket-quota"],
+            StoreOption,
+            "Number of trajectories each quota bucket takes (default: the trajectory count \
+             divided by the number of buckets)",
+        );
+        argparse.refer(&mut quota_budget).add_option(
+            &["--quota-budget"],
+            StoreOption,
+            "Stop a search with quotas after trying this many candidates, even if some buckets \
+             aren't full. The budget is checked after each unit of work (default: 10000 \
+             candidates for every trajectory the buckets take)",
+        );
+        argparse.refer(&mut conf.max_iterations).add_option(
             &["--max-iters"],
             Store,
             "Maximum number of allowed iterations.",
         );
-        argparse.refer(&mut min_iterations).add_option(
+        argparse.refer(&mut conf.min_iterations).add_option(
             &["--min-iters"],
             Store,
             "Minimum required number of iterations.",
         );
+        argparse.refer(&mut seed).add_option(
+            &["--seed"],
+            StoreOption,
+            "Seed for the random numbers used in the search. The same seed always finds the same \
+             trajectories in the same order, whatever the number of threads (default: random)",
+        );
+        argparse.refer(&mut unit_size).add_option(
+            &["--unit-size"],
+            Store,
+            "Number of trajectories in each unit of work handed to a thread. Changing this \
+             changes which trajectories a seed finds (default 64)",
+        );
+        argparse.refer(&mut stall_seconds).add_option(
+            &["--stall-timeout"],
+            Store,
+            "Warn on stderr whenever this many seconds pass without finding a trajectory \
+             (default 60, 0 disables the warning)",
+        );
+        argparse.refer(&mut time_budget_seconds).add_option(
+            &["--time-budget"],
+            StoreOption,
+            "Stop searching after this many seconds, writing out everything found so far",
+        );
+        argparse.refer(&mut conf.iteration_budget).add_option(
+            &["--iteration-budget"],
+            StoreOption,
+            "Stop searching once the candidates tried have been iterated this many times in all. \
+             The budget is checked after each unit of work, so the output still only depends on \
+             the seed",
+        );
+        argparse.refer(&mut stall_stop_seconds).add_option(
+            &["--stop-after-stall"],
+            StoreOption,
+            "Stop searching once this many seconds pass without finding a trajectory to keep \
+             (with quotas, one for a bucket which isn't full yet), writing out everything found \
+             so far",
+        );
+        argparse.refer(&mut conf.orbits).add_option(
+            &["--orbits"],
+            Store,
+            "Which orbits to collect: 'escaping' for a Buddhabrot, 'bounded' (or 'anti') for an \
+             anti-Buddhabrot of the orbits which never escape, or 'both' (default escaping)",
+        );
+        argparse.refer(&mut conf.formula).add_option(
+            &["--formula"],
+            Store,
+            "The map to iterate: 'mandelbrot', 'burning-ship', 'tricorn' or 'celtic'. Other \
+             formulas may need a different sampling region (default mandelbrot)",
+        );
+        argparse.refer(&mut conf.exponent).add_option(
+            &["--exponent"],
+            Store,
+            "Iterate z = z^d + c with this exponent d, which may be fractional, to make a \
+             Multibrot. Other exponents may need a different sampling region (default 2)",
+        );
+        argparse.refer(&mut conf.sampler).add_option(
+            &["--sampler"],
+            Store,
+            "How candidate points are chosen: 'uniform', 'metropolis', 'importance', or the \
+             evenly spread 'halton', 'sobol' or 'stratified', or 'boundary' to only sample \
+             near the boundary of the set, or 'landmarks' to only sample around centres of \
+             hyperbolic components and Misiurewicz points (default uniform)",
+        );
+        argparse.refer(&mut explore).add_option(
+            &["--explore"],
+            StoreTrue,
+            "Explore the neighbourhood of every trajectory found, on top of the chosen sampler \
+             ('uniform', 'halton', 'sobol' or 'stratified')",
+        );
+        argparse.refer(&mut explore_conf.samples).add_option(
+            &["--explore-samples"],
+            Store,
+            "Number of candidates drawn around each trajectory found when exploring (default 16)",
+        );
+        argparse.refer(&mut explore_conf.share).add_option(
+            &["--explore-share"],
+            Store,
+            "Fraction of the candidates drawn around earlier finds rather than by the chosen \
+             sampler, while there are finds left to explore (default 0.5)",
+        );
+        argparse.refer(&mut explore_conf.radius).add_option(
+            &["--explore-radius"],
+            Store,
+            "Distance of the first candidate drawn around a find, as a fraction of the smaller \
+             side of the sampling region (default 0.001)",
+        );
+        argparse.refer(&mut explore_conf.shrink).add_option(
+            &["--explore-shrink"],
+            Store,
+            "Factor the distance shrinks by with each candidate drawn around a find (default 0.7)",
+        );
+        argparse.refer(&mut explore_conf.dedup).add_option(
+            &["--explore-dedup"],
+            Store,
+            "Drop trajectories starting within this distance of one already found in the same \
+             unit of work when exploring (default 1e-10)",
+        );
+        argparse.refer(&mut boundary_grid).add_option(
+            &["--boundary-grid"],
+            Store,
+            "Number of cells along each side of the coarse grid the boundary sampler starts \
+             from (default 64)",
+        );
+        argparse.refer(&mut boundary_depth).add_option(
+            &["--boundary-depth"],
+            Store,
+            "Number of times the boundary sampler splits the boundary cells of its grid into \
+             four finer cells (default 3)",
+        );
+        argparse.refer(&mut landmark_period).add_option(
+            &["--landmark-period"],
+            Store,
+            "Longest period of the centres and Misiurewicz points the landmark sampler looks for \
+             (default 8)",
+        );
+        argparse.refer(&mut landmark_preperiod).add_option(
+            &["--landmark-preperiod"],
+            Store,
+            "Longest preperiod of the Misiurewicz points the landmark sampler looks for, or 0 \
+             for centres only (default 3)",
+        );
+        argparse.refer(&mut conf.landmark_radius).add_option(
+            &["--landmark-radius"],
+            Store,
+            "Radius of the disc the landmark sampler draws from around each landmark, as a \
+             multiple of the size of the set's features there (default 1)",
+        );
+        argparse.refer(&mut save_landmarks_file).add_option(
+            &["--save-landmarks"],
+            StoreOption,
+            "Save the landmarks to this file once they're found, so later runs can load them",
+        );
+        argparse.refer(&mut load_landmarks_file).add_option(
+            &["--load-landmarks"],
+            StoreOption,
+            "Load the landmarks from this file instead of looking for them",
+        );
+        argparse.refer(&mut conf.strata).add_option(
+            &["--strata"],
+            Store,
+            "Number of cells along each side of the stratified sampler's grid (default 64)",
+        );
+        argparse.refer(&mut importance_size).add_option(
+            &["--importance-size"],
+            Store,
+            "Number of cells along each side of the importance sampler's map (default 128)",
+        );
+        argparse.refer(&mut importance_samples).add_option(
+            &["--importance-samples"],
+            Store,
+            "Number of points tried along each side of every cell of the importance sampler's \
+             map (default 4)",
+        );
+        argparse.refer(&mut save_importance_file).add_option(
+            &["--save-importance-map"],
+            StoreOption,
+            "Save the importance sampler's map to this file once it's built, so later runs can \
+             load it",
+        );
+        argparse.refer(&mut load_importance_file).add_option(
+            &["--load-importance-map"],
+            StoreOption,
+            "Load the importance sampler's map from this file instead of building one. Candidates \
+             are drawn from the region the map covers",
+        );
+        argparse.refer(&mut conf.large_step_prob).add_option(
+            &["--large-step-prob"],
+            Store,
+            "Probability that the metropolis sampler jumps to a brand new random point instead of \
+             mutating the current one (default 0.1)",
+        );
+        argparse.refer(&mut chain_length).add_option(
+            &["--chain-length"],
+            StoreOption,
+            "Number of trajectories each of the metropolis sampler's chains emits. Every chain is \
+             its own unit of work, so this takes the place of --unit-size (default: the unit \
+             size)",
+        );
+        argparse.refer(&mut conf.region.startx).add_option(
+            &["--re-min"],
+            Store,
+            "Smallest real part of the sampled points (default -2.5, or -2.0 for Julia sets)",
+        );
+        argparse.refer(&mut conf.region.stopx).add_option(
+            &["--re-max"],
+            Store,
+            "Largest real part of the sampled points (default 1.0, or 2.0 for Julia sets)",
+        );
+        argparse.refer(&mut conf.region.starty).add_option(
+            &["--im-min"],
+            Store,
+            "Smallest imaginary part of the sampled points (default -1.0, or -2.0 for Julia sets)",
+        );
+        argparse.refer(&mut conf.region.stopy).add_option(
+            &["--im-max"],
+            Store,
+            "Largest imaginary part of the sampled points (default 1.0, or 2.0 for Julia sets)",
+        );
+        argparse.refer(&mut conf.cycle_epsilon).add_option(
+            &["--cycle-epsilon"],
+            Store,
+            "Orbits which come back to within this distance of an earlier point are rejected as \
+             cycling. Larger values reject bounded orbits sooner but may reject orbits which \
+             would have escaped (default 1e-12)",
+        );
+        argparse.refer(&mut conf.distance_filter).add_option(
+            &["--distance-filter"],
+            StoreTrue,
+            "Estimate each candidate's distance from the set with a few iterations first, and \
+             reject those too far away to last --min-iters iterations without iterating them \
+             fully. Only the mandelbrot formula with an exponent of 2 has an estimate",
+        );
+        argparse.refer(&mut conf.distance_iterations).add_option(
+            &["--distance-iters"],
+            Store,
+            "Number of iterations used for the distance estimate (default 64)",
+        );
+        argparse.refer(&mut julia_re).add_option(
+            &["--julia-re"],
+            StoreOption,
+            "Search the Julia set of the constant c with this real part, sampling the starting z \
+             of each orbit instead of c",
+        );
+        argparse.refer(&mut julia_im).add_option(
+            &["--julia-im"],
+            StoreOption,
+            "Search the Julia set of the constant c with this imaginary part, sampling the \
+             starting z of each orbit instead of c",
+        );
+        argparse.refer(&mut build_mask).add_option(
+            &["--interior-mask"],
+            StoreTrue,
+            "Build a mask of the cells of the sampling region which lie inside the set before \
+             searching, and skip candidates which land in them without iterating them",
+        );
+        argparse.refer(&mut mask_size).add_option(
+            &["--interior-mask-size"],
+            Store,
+            "Number of cells along each side of the interior mask (default 256)",
+        );
+        argparse.refer(&mut save_mask_file).add_option(
+            &["--save-interior-mask"],
+            StoreOption,
+            "Save the interior mask to this file once it's built, so later runs can load it",
+        );
+        argparse.refer(&mut load_mask_file).add_option(
+            &["--load-interior-mask"],
+            StoreOption,
+            "Load the interior mask from this file instead of building one. The mask must have \
+             been built for the same formula and exponent",
+        );
+        argparse.refer(&mut conf.min_view_hits).add_option(
+            &["--min-view-hits"],
+            Store,
+            "Only accept trajectories with at least this many points inside the target viewport \
+             (default 0, which disables the viewport check)",
+        );
+        argparse.refer(&mut conf.viewport.center.re).add_option(
+            &["--view-center-re"],
+            Store,
+            "Real part of the center of the target viewport (default -0.375)",
+        );
+        argparse.refer(&mut conf.viewport.center.im).add_option(
+            &["--view-center-im"],
+            Store,
+            "Imaginary part of the center of the target viewport (default 0.0)",
+        );
+        argparse.refer(&mut conf.viewport.zoom).add_option(
+            &["--view-zoom"],
+            Store,
+            "Zoom of the target viewport, where 1.0 is 2.25 units tall (default 1.0)",
+        );
+        argparse.refer(&mut conf.viewport.aspect).add_option(
+            &["--view-aspect"],
+            Store,
+            "Width divided by height of the target viewport (default 1.0)",
+        );
+        argparse.refer(&mut view_rotate_degrees).add_option(
+            &["--view-rotate"],
+            Store,
+            "Degrees the target viewport is rotated counter-clockwise around its center \
+             (default 0)",
+        );
         argparse.parse_args_or_exit();
     }
+    conf.viewport.rotation = view_rotate_degrees.to_radians();
+    if julia_re.is_some() || julia_im.is_some() {
+        conf.julia = Some(Complex::new(
+            julia_re.unwrap_or(0.0),
+            julia_im.unwrap_or(0.0),
+        ));
+        if conf.region == Region::default() {
+            conf.region = Region::julia();
+        }
+    }
+    if thread_count == 0 {
+        eprintln!("At least 1 thread is needed");
+        std::process::exit(2);
+    }
+    if conf.exponent.is_nan() || conf.exponent <= 1.0 {
+        eprintln!("The exponent must be greater than 1");
+        std::process::exit(2);
+    }
+    if conf.region.xspan() <= 0.0 || conf.region.yspan() <= 0.0 {
+        eprintln!("The sampling region must have a positive width and height");
+        std::process::exit(2);
+    }
+    if conf.sampler == SamplerKind::Stratified && conf.strata == 0 {
+        eprintln!("The stratified sampler needs at least 1 stratum along each side");
+        std::process::exit(2);
+    }
+    if explore {
+        if conf.sampler.is_weighted() {
+            eprintln!(
+                "Exploring only works on top of the uniform, halton, sobol or stratified samplers, \
+                 since it would skew the weights of the others"
+            );
+            std::process::exit(2);
+        }
+        let valid = (0.0..=1.0).contains(&explore_conf.share)
+            && explore_conf.radius > 0.0
+            && explore_conf.shrink > 0.0
+            && explore_conf.shrink <= 1.0
+            && !explore_conf.dedup.is_nan();
+        if !valid {
+            eprintln!(
+                "The explore share must be between 0 and 1, the radius positive, and the shrink \
+                 factor between 0 and 1"
+            );
+            std::process::exit(2);
+        }
+        conf.explore = Some(explore_conf);
+    }
+    if let Some(length) = chain_length {
+        if conf.sampler != SamplerKind::Metropolis {
+            eprintln!("Chain lengths are only used by the metropolis sampler");
+            std::process::exit(2);
+        }
+        unit_size = length;
+    }
+    if unit_size == 0 {
+        eprintln!("The unit size and the chain length must be at least 1");
+        std::process::exit(2);
+    }
+    if quota_buckets > 0 {
+        let target = bucket_quota.unwrap_or_else(|| trajectory_count.div_ceil(quota_buckets));
+        if target == 0 || quota_budget == Some(0) || conf.min_iterations > conf.max_iterations {
+            eprintln!(
+                "Quota buckets must take at least 1 trajectory each, the quota budget must be at \
+                 least 1 candidate, and --min-iters can't be above --max-iters"
+            );
+            std::process::exit(2);
+        }
+        if conf.orbits == Orbits::Bounded {
+            eprintln!(
+                "Quotas can't be used with --orbits bounded: every bounded orbit lasts \
+                 --max-iters iterations, so only the last bucket could ever fill"
+            );
+            std::process::exit(2);
+        }
+        let quotas = Quotas::new(
+            conf.min_iterations,
+            conf.max_iterations,
+            quota_buckets,
+            target,
+        );
+        eprintln!(
+            "Taking {} trajectories in each of {} quota buckets",
+            target,
+            quotas.bucket_count()
+        );
+        conf.quota_budget = quota_budget.unwrap_or_else(|| {
+            (target as u64)
+                .saturating_mul(quotas.bucket_count() as u64)
+                .saturating_mul(DEFAULT_QUOTA_BUDGET_PER_TRAJECTORY)
+        });
+        conf.quotas = Some(Arc::new(quotas));
+    } else if bucket_quota.is_some() || quota_budget.is_some() {
+        eprintln!("Quotas need --quota-buckets");
+        std::process::exit(2);
+    }
+    if conf.distance_filter && conf.julia.is_some() {
+        eprintln!("Ignoring the distance filter, which only applies to c values, for a Julia set");
+        conf.distance_filter = false;
+    } else if conf.distance_filter && conf.formula != Formula::Mandelbrot {
+        eprintln!("Ignoring the distance filter, which only applies to the mandelbrot formula");
+        conf.distance_filter = false;
+    } else if conf.distance_filter && conf.exponent != MANDELBROT_EXPONENT {
+        eprintln!("Ignoring the distance filter, whose cutoff only holds for an exponent of 2");
+        conf.distance_filter = false;
+    }
+    let function = match conf.julia {
+        Some(c) => FormulaMap::new(conf.formula, conf.exponent).for_julia(c),
+        None => FormulaMap::new(conf.formula, conf.exponent),
+    };
+    if conf.sampler == SamplerKind::Importance {
+        conf.importance = Some(importance_map(
+            function,
+            importance_size,
+            importance_samples,
+            load_importance_file,
+            save_importance_file,
+            thread_count,
+            &mut conf,
+        ));
+    } else if load_importance_file.is_some() || save_importance_file.is_some() {
+        eprintln!("Importance maps are only used by the importance sampler");
+        std::process::exit(2);
+    }
+    if conf.sampler == SamplerKind::Landmarks {
+        conf.landmarks = Some(find_or_load_landmarks(
+            function,
+            landmark_period,
+            landmark_preperiod,
+            load_landmarks_file,
+            save_landmarks_file,
+            thread_count,
+            &conf,
+        ));
+    } else if load_landmarks_file.is_some() || save_landmarks_file.is_some() {
+        eprintln!("Landmarks are only used by the landmark sampler");
+        std::process::exit(2);
+    }
+    let mask = if conf.julia.is_some() {
+        if build_mask || load_mask_file.is_some() {
+            eprintln!(
+                "Ignoring the interior mask, which only applies to c values, for a Julia set"
+            );
+        }
+        None
+    } else {
+        interior_mask(
+            function,
+            build_mask,
+            mask_size,
+            load_mask_file,
+            save_mask_file,
+            thread_count,
+            &conf,
+        )
+    };
+    // Always report the seed, so that any run can be reproduced later.
+    let seed = seed.unwrap_or_else(|| rand::thread_rng().gen());
+    eprintln!("Using seed {}", seed);
+    // Zero or negative timeouts turn the warning off, but any other timeout has to fit in a
+    // Duration.
+    let stall_timeout = match Duration::try_from_secs_f64(stall_seconds) {
+        Ok(timeout) if !timeout.is_zero() => Some(timeout),
+        Ok(_) => None,
+        Err(_) if stall_seconds < 0.0 => None,
+        Err(_) => {
+            eprintln!("The stall timeout must be a finite number of seconds");
+            std::process::exit(2);
+        }
+    };
+    let positive = |seconds: f64| {
+        Duration::try_from_secs_f64(seconds)
+            .ok()
+            .filter(|d| !d.is_zero())
+    };
+    let time_budget = time_budget_seconds.map(positive);
+    let stall_stop = stall_stop_seconds.map(positive);
+    if time_budget == Some(None) || stall_stop == Some(None) {
+        eprintln!("The time budget and the stall limit must be a positive number of seconds");
+        std::process::exit(2);
+    }
+    conf.time_budget = time_budget.flatten();
+    conf.stall_stop = stall_stop.flatten();
+    let function = Masked::new(function, mask);
+    if conf.sampler == SamplerKind::Boundary {
+        if boundary_grid == 0
+            || boundary_depth > 30
+            || boundary_grid << boundary_depth > u32::MAX as usize / 2
+        {
+            eprintln!(
+                "The boundary grid must have at least 1 cell, and can't be refined that deep"
+            );
+            std::process::exit(2);
+        }
+        let cells = BoundaryCells::build(
+            &function,
+            conf.julia,
+            conf.max_iterations,
+            conf.region,
+            boundary_grid,
+            boundary_depth,
+            thread_count,
+        );
+        if cells.is_empty() {
+            eprintln!("The boundary grid didn't find the boundary of the set in the region");
+            std::process::exit(2);
+        }
+        let counts: Vec<String> = (0..cells.level_count())
+            .map(|level| cells.cell_count(level).to_string())
+            .collect();
+        eprintln!("Boundary cells at each level: {}", counts.join(", "));
+        conf.boundary = Some(Arc::new(cells));
+    }
     coordinate_search(
+        function,
         thread_count,
         trajectory_count,
-        max_iterations,
-        min_iterations,
+        unit_size,
+        seed,
+        stall_timeout,
+        conf,
     )
 }
 
 // Function to coordinate other functions
 // Function to search for candidates and write them to a channel
 // Function to recieve from the channel and write them to the output of choice
-fn coordinate_search(
+fn coordinate_search<F>(
+    function: F,
     thread_count: usize,
     trajectory_count: usize,
-    max_iterations: i64,
-    min_iterations: i64,
-) {
+    unit_size: usize,
+    seed: u64,
+    stall_timeout: Option<Duration>,
+    conf: SearchConf,
+) where
+    F: IterationFunction + Clone + Send + 'static,
+{
     // Choose an output file based on the current time. This file name is a good candidate for a
     // user-providable CLI parameter in the future.
     //let filename = time::strftime("trajectory_candidates_%Y-%m-%d__%H-%M-%S.json", &time::now()).unwrap();
 
-    // Set up the variables necessary for candidate searching
-    //
-    // Calculate the number of trajectories each thread should search for. We do integer division
-    // to find the number of trajectories each thread should search for, so the total count may be
-    // less than the count specified on the CLI
-    let per_thread_traj_count: usize = trajectory_count / thread_count;
-    let total_trajectory_count: usize = per_thread_traj_count * thread_count;
-    // Just kidding; we want all threads to be working, so tell each to find all the trajs; all
-    // threads will work all the time in this case and once we reach the max, then we're done
-    let thread_trajectories: Vec<usize> = (0..thread_count).map(|_| trajectory_count).collect();
-
-    // Start the threads that do the searching
+    let header = TrajectoryHeader {
+        min_length: conf.min_iterations,
+        max_length: conf.max_iterations,
+    };
+    println!("{}", serde_json::to_string(&header).unwrap());
+
+    // The search is split into numbered units of `unit_size` trajectories (the last unit gets
+    // whatever is left over). Each unit is searched with its own stream of random numbers derived
+    // from `seed` and the unit's number, and units are written out in order, so the output only
+    // depends on the seed and never on how many threads there are or which thread searched which
+    // unit. With quotas there's no telling how many units it takes to fill every bucket, so units
+    // are handed out until the quotas say the run is finished.
+    let started = Instant::now();
+    let unit_count = match conf.quotas {
+        Some(_) => usize::MAX,
+        None => trajectory_count.div_ceil(unit_size),
+    };
+    let next_unit = Arc::new(AtomicUsize::new(0));
+    let stop = Arc::new(AtomicBool::new(false));
+    let watchdog = Watchdog::start("trajectories found", stall_timeout);
     let (sender, reciever) = channel();
-    for trajs in thread_trajectories.iter() {
+    let mut children = vec![];
+    for _ in 0..thread_count {
         let child_sender = sender.clone();
-        let t = trajs.clone();
-        let _ = thread::spawn(move || {
-            search_and_transmit(t, max_iterations, min_iterations, child_sender);
-        });
+        let child_next_unit = next_unit.clone();
+        let child_stop = stop.clone();
+        let ticker = watchdog.ticker();
+        let function = function.clone();
+        let conf = conf.clone();
+        children.push(thread::spawn(move || loop {
+            if child_stop.load(Ordering::Relaxed) {
+                break;
+            }
+            let unit = child_next_unit.fetch_add(1, Ordering::Relaxed);
+            if unit >= unit_count {
+                break;
+            }
+            let quota = match conf.quotas {
+                Some(_) => unit_size,
+                None => min(unit_size, trajectory_count - unit * unit_size),
+            };
+            let mut sampler = new_sampler(seed, unit as u64, &conf);
+            let (trajectories, stats) = search_unit(
+                sampler.as_mut(),
+                &function,
+                quota,
+                &conf,
+                &ticker,
+                &child_stop,
+            );
+            if child_sender.send((unit, trajectories, stats)).is_err() {
+                break;
+            }
+        }));
+    }
+    // Only the threads hold senders now, so the channel disconnects once every thread is done.
+    drop(sender);
+
+    // Recieve all the trajectories and print them (for now). Units finish out of order, so each
+    // is held onto until every unit before it has been printed. The watchdog only complains on
+    // stderr when the search is slow; the stop conditions are what end it early.
+    //
+    // The conditions checked in unit order (full quotas and the candidate and iteration budgets)
+    // throw away the units after the one which met them, so that neither the trajectories nor the
+    // stats depend on how far the other threads had got. The conditions on time can't be
+    // reproduced anyway, so they write out everything found so far, including the trajectories
+    // of the units the threads were part way through.
+    let mut finished = BTreeMap::new();
+    let mut next_to_print = 0;
+    let mut stats = SearchStats::default();
+    let mut written = 0;
+    let mut last_progress = (watchdog.progress(), Instant::now());
+    let reason = loop {
+        match reciever.recv_timeout(STOP_POLL_INTERVAL) {
+            Ok((unit, trajectories, unit_stats)) => {
+                finished.insert(unit, (trajectories, unit_stats));
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break StopReason::Found,
+        }
+        let mut reason = None;
+        while let Some((trajectories, unit_stats)) = finished.remove(&next_to_print) {
+            write_unit(trajectories, unit_stats, &mut stats, &mut written, &conf);
+            next_to_print += 1;
+            reason = unit_order_stop(&stats, &conf);
+            if reason.is_some() {
+                break;
+            }
+        }
+        if let Some(reason) = reason {
+            break reason;
+        }
+        let progress = watchdog.progress();
+        if progress != last_progress.0 {
+            last_progress = (progress, Instant::now());
+        }
+        if let Some(budget) = conf.time_budget.filter(|&b| started.elapsed() >= b) {
+            break StopReason::TimeBudget(budget);
+        }
+        if let Some(limit) = conf.stall_stop.filter(|&l| last_progress.1.elapsed() >= l) {
+            break StopReason::Stalled(limit);
+        }
+    };
+    stop.store(true, Ordering::Relaxed);
+    match reason {
+        StopReason::TimeBudget(_) | StopReason::Stalled(_) => {
+            // The threads hand over what they have as soon as they notice the stop.
+            for (unit, trajectories, unit_stats) in reciever.iter() {
+                finished.insert(unit, (trajectories, unit_stats));
+            }
+            for (_, (trajectories, unit_stats)) in finished {
+                write_unit(trajectories, unit_stats, &mut stats, &mut written, &conf);
+            }
+        }
+        _ => drop(reciever),
     }
+    for child in children {
+        child.join().expect("a search thread panicked");
+    }
+    io::stdout()
+        .flush()
+        .expect("couldn't write the trajectories");
+    eprintln!(
+        "Tried {} candidates ({} iterations) in {:.2}s, writing {} trajectories",
+        stats.candidates,
+        stats.iterations,
+        started.elapsed().as_secs_f64(),
+        written
+    );
+    eprintln!("Stopped: {}", reason);
+    if conf.distance_filter {
+        eprintln!(
+            "Rejected {} candidates ({:.1}%) by their distance estimate",
+            stats.distance_rejected,
+            100.0 * stats.distance_rejected as f64 / stats.candidates.max(1) as f64
+        );
+    }
+    if conf.explore.is_some() {
+        eprintln!(
+            "Explored {} candidates ({:.1}%), finding {} trajectories, and dropped {} duplicates",
+            stats.explored_candidates,
+            100.0 * stats.explored_candidates as f64 / stats.candidates.max(1) as f64,
+            stats.explored_accepted,
+            stats.duplicates
+        );
+    }
+    if let Some(ref quotas) = conf.quotas {
+        eprintln!("Bucket     Lengths           Found / Quota");
+        for bucket in 0..quotas.bucket_count() {
+            let (shortest, longest) = quotas.lengths(bucket);
+            eprintln!(
+                "{:>6}  {:>8} - {:<8}  {:>6} / {}",
+                bucket,
+                shortest,
+                longest,
+                quotas.count(bucket),
+                quotas.target()
+            );
+        }
+    }
+    if let Some(ref cells) = conf.boundary {
+        eprintln!("Level  Cells  Candidates  Accepted");
+        for level in 0..cells.level_count() {
+            let candidates = stats.level_candidates.get(level).cloned().unwrap_or(0);
+            let accepted = stats.level_accepted.get(level).cloned().unwrap_or(0);
+            eprintln!(
+                "{:>5}  {:>5}  {:>10}  {:>8} ({:.2}%)",
+                level,
+                cells.cell_count(level),
+                candidates,
+                accepted,
+                100.0 * accepted as f64 / candidates.max(1) as f64
+            );
+        }
+    }
+}
+
+/// The candidates a search with quotas may try for each trajectory its buckets take, unless
+/// --quota-budget says otherwise.
+const DEFAULT_QUOTA_BUDGET_PER_TRAJECTORY: u64 = 10_000;
 
-    // Start the function for recieving and writing to the file
+/// How often the search checks the stop conditions on time.
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(50);
 
-    // If the program is failing to find *anything* for long enough, we want it to time out and
-    // just print what we've got. The timeout is thus based on a bare minimum, 1/4 of a second,
-    // plus a number of milliseconds equal to 100 * the minimum number of iterations. It's a pretty
-    // usable heuristic.
-    let timeout = Duration::from_millis(250 + (100 * min_iterations) as u64);
+/// Why a search stopped.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum StopReason {
+    Found,
+    QuotasFilled,
+    CandidateBudget(u64),
+    IterationBudget(u64),
+    TimeBudget(Duration),
+    Stalled(Duration),
+}
 
-    // Recieve all the trajectories and print them (for now)
-    for _ in 0..total_trajectory_count {
-        match reciever.recv_timeout(timeout) {
-            Ok(trajectory) => {
-                println!("{}", serde_json::to_string(&trajectory).unwrap());
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StopReason::Found => write!(f, "found every trajectory asked for"),
+            StopReason::QuotasFilled => write!(f, "filled every quota bucket"),
+            StopReason::CandidateBudget(n) => write!(f, "spent the budget of {} candidates", n),
+            StopReason::IterationBudget(n) => write!(f, "spent the budget of {} iterations", n),
+            StopReason::TimeBudget(d) => {
+                write!(f, "spent the time budget of {:.1}s", d.as_secs_f64())
             }
-            Err(_) => {
-                println!("\n\nTimed Out!\n\n");
-                break;
+            StopReason::Stalled(d) => {
+                write!(f, "found no trajectory in the last {:.1}s", d.as_secs_f64())
             }
         }
     }
 }
 
-// tells us if a point in the complex plane will loop forever by telling us if it's within the main
-// cardiod or within the second-order bulb. This returning false doesn't guarantee that there's a
-// finite number of loops, as this is just a quick special case to speed things up.
-fn will_loop_forever(z: Complex) -> bool {
-    let x = z.re;
-    let y = z.im;
-    let p: f64 = ((x - 0.25).powi(2) + y.powi(2)).sqrt();
-    if x < (p - (2.0 * p.powi(2)) + 0.25) {
-        return true;
+// Checks the stop conditions which only depend on the units written out so far.
+fn unit_order_stop(stats: &SearchStats, conf: &SearchConf) -> Option<StopReason> {
+    if let Some(ref quotas) = conf.quotas {
+        if quotas.all_full() {
+            return Some(StopReason::QuotasFilled);
+        }
+        if stats.candidates >= conf.quota_budget {
+            return Some(StopReason::CandidateBudget(conf.quota_budget));
+        }
     }
-    if ((x + 1.0).powi(2) + y.powi(2)) < 0.0625 {
-        return true;
+    match conf.iteration_budget {
+        Some(budget) if stats.iterations >= budget => Some(StopReason::IterationBudget(budget)),
+        _ => None,
     }
-    return false;
 }
-fn search_and_transmit(
-    trajectory_count: usize,
-    max_iterations: i64,
-    min_iterations: i64,
-    sender: Sender<Trajectory>,
+
+// Writes out the trajectories of a unit, leaving out those whose quota bucket is already full,
+// and adds its stats to the totals.
+fn write_unit(
+    trajectories: Vec<Trajectory>,
+    unit_stats: SearchStats,
+    stats: &mut SearchStats,
+    written: &mut u64,
+    conf: &SearchConf,
 ) {
-    let mut rng = rand::thread_rng();
-    let mut valid_trajectory_count = 0;
-    // centerx : hard coded at -0.75
-    // centery : hard coded at 0
-    // x span: [-2.5, 1.0]
-    // y span: [-1.0, 1.0]
-    //
-    let (startx, stopx): (f64, f64) = (-2.5, 1.0);
-    let (starty, stopy): (f64, f64) = (-1.0, 1.0);
-    let xspan = stopx - startx;
-    let yspan = stopy - starty;
-
-    while valid_trajectory_count < trajectory_count {
-        let mut escaped = false;
-        let mut z = Complex::new(0.0, 0.0);
-        let cn = Complex::new(
-            startx + rng.gen::<f64>() * xspan,
-            starty + rng.gen::<f64>() * yspan,
-        );
-        let mut trajectory: Trajectory = Trajectory {
-            init_c: cn,
-            waypoints: Vec::new(),
-            length: 0,
+    stats.add(unit_stats);
+    for trajectory in trajectories {
+        if let Some(ref quotas) = conf.quotas {
+            match quotas.take(trajectory.length) {
+                Some(bucket) if quotas.is_full(bucket) => {
+                    let (shortest, longest) = quotas.lengths(bucket);
+                    eprintln!(
+                        "Filled the bucket of orbits lasting {} to {} iterations after {} \
+                         candidates",
+                        shortest, longest, stats.candidates
+                    );
+                }
+                Some(_) => {}
+                None => continue,
+            }
+        }
+        println!("{}", serde_json::to_string(&trajectory).unwrap());
+        *written += 1;
+    }
+}
+
+// Builds or loads the importance sampler's map, saving it when asked to. A loaded map replaces
+// the sampling region with the one it covers.
+fn importance_map(
+    function: FormulaMap,
+    size: usize,
+    samples: usize,
+    load_file: Option<String>,
+    save_file: Option<String>,
+    thread_count: usize,
+    conf: &mut SearchConf,
+) -> Arc<ImportanceMap> {
+    let map = if let Some(fname) = load_file {
+        let map = load_importance_map(fname.clone()).unwrap_or_else(|e| {
+            eprintln!("Couldn't load the importance map {}: {}", fname, e);
+            std::process::exit(2);
+        });
+        let params = *map.params();
+        if params.function != function || params.julia != conf.julia {
+            eprintln!(
+                "The importance map {} was built for {:?} with a Julia constant of {:?}",
+                fname, params.function, params.julia
+            );
+            std::process::exit(2);
+        }
+        if params.min_iterations != conf.min_iterations
+            || params.max_iterations != conf.max_iterations
+        {
+            eprintln!(
+                "Warning: the importance map {} was built for orbits lasting {} to {} iterations",
+                fname, params.min_iterations, params.max_iterations
+            );
+        }
+        conf.region = Region {
+            startx: params.re_min,
+            stopx: params.re_max,
+            starty: params.im_min,
+            stopy: params.im_max,
         };
-        if will_loop_forever(cn) {
-            continue;
+        map
+    } else {
+        if size == 0 || samples == 0 {
+            eprintln!("The importance map size and samples must be at least 1");
+            std::process::exit(2);
         }
-        let mut periods = HashMap::new();
-        for itercount in 0..max_iterations {
-            trajectory.length = itercount as i64;
-            if escaped {
-                break;
+        let params = ImportanceParams {
+            function,
+            julia: conf.julia,
+            min_iterations: conf.min_iterations,
+            max_iterations: conf.max_iterations,
+            re_min: conf.region.startx,
+            re_max: conf.region.stopx,
+            im_min: conf.region.starty,
+            im_max: conf.region.stopy,
+        };
+        ImportanceMap::build(params, size, size, samples, thread_count)
+    };
+    eprintln!(
+        "Using a {}x{} importance map with hits in {:.1}% of its cells",
+        map.width(),
+        map.height(),
+        map.coverage() * 100.0
+    );
+    if let Some(fname) = save_file {
+        if let Err(e) = save_importance_map(&map, fname.clone()) {
+            eprintln!("Couldn't save the importance map {}: {}", fname, e);
+            std::process::exit(2);
+        }
+    }
+    Arc::new(map)
+}
+
+// Finds or loads the landmark sampler's landmarks, saving them when asked to. Only the landmarks
+// inside the sampling region are used.
+fn find_or_load_landmarks(
+    function: FormulaMap,
+    max_period: u32,
+    max_preperiod: u32,
+    load_file: Option<String>,
+    save_file: Option<String>,
+    thread_count: usize,
+    conf: &SearchConf,
+) -> Arc<LandmarkDiscs> {
+    if conf.julia.is_some() || !landmarks::supported(&function) {
+        eprintln!(
+            "Landmarks can only be found for the mandelbrot formula with a whole exponent, and \
+             not for Julia sets"
+        );
+        std::process::exit(2);
+    }
+    let valid = conf.landmark_radius > 0.0 && max_period >= 1 && max_period + max_preperiod <= 64;
+    if !valid {
+        eprintln!(
+            "The landmark radius must be positive, and the period at least 1 and at most 64 \
+             with the preperiod"
+        );
+        std::process::exit(2);
+    }
+    let (params, found) = if let Some(fname) = load_file {
+        let (params, found) = load_landmarks(fname.clone()).unwrap_or_else(|e| {
+            eprintln!("Couldn't load the landmarks {}: {}", fname, e);
+            std::process::exit(2);
+        });
+        if params.function != function {
+            eprintln!(
+                "The landmarks {} were found for {:?}",
+                fname, params.function
+            );
+            std::process::exit(2);
+        }
+        if params.max_period != max_period || params.max_preperiod != max_preperiod {
+            eprintln!(
+                "Warning: the landmarks {} were found up to period {} and preperiod {}, rather \
+                 than the {} and {} asked for",
+                fname, params.max_period, params.max_preperiod, max_period, max_preperiod
+            );
+        }
+        (params, found)
+    } else {
+        let params = LandmarkParams {
+            function,
+            max_period,
+            max_preperiod,
+            re_min: conf.region.startx,
+            re_max: conf.region.stopx,
+            im_min: conf.region.starty,
+            im_max: conf.region.stopy,
+        };
+        let found = find_landmarks(&params, thread_count);
+        (params, found)
+    };
+    if let Some(fname) = save_file {
+        if let Err(e) = save_landmarks(&params, &found, fname.clone()) {
+            eprintln!("Couldn't save the landmarks {}: {}", fname, e);
+            std::process::exit(2);
+        }
+    }
+    let found: Vec<Landmark> = found
+        .into_iter()
+        .filter(|landmark| conf.region.contains(landmark.c))
+        .collect();
+    if found.is_empty() {
+        eprintln!("There are no landmarks in the sampling region");
+        std::process::exit(2);
+    }
+    let centres = found.iter().filter(|l| l.preperiod == 0).count();
+    eprintln!(
+        "Using {} centres and {} Misiurewicz points, up to period {} and preperiod {}",
+        centres,
+        found.len() - centres,
+        params.max_period,
+        params.max_preperiod
+    );
+    Arc::new(LandmarkDiscs::new(found, conf.landmark_radius, conf.region))
+}
+
+// Builds or loads the interior mask asked for on the command line, if any, saving it when asked
+// to. Problems with the mask are fatal, since they mean the command line was wrong.
+fn interior_mask(
+    function: FormulaMap,
+    build: bool,
+    size: usize,
+    load_file: Option<String>,
+    save_file: Option<String>,
+    thread_count: usize,
+    conf: &SearchConf,
+) -> Option<Arc<InteriorMask>> {
+    let mask = if let Some(fname) = load_file {
+        let mask = load_mask(fname.clone()).unwrap_or_else(|e| {
+            eprintln!("Couldn't load the interior mask {}: {}", fname, e);
+            std::process::exit(2);
+        });
+        if mask.params().function != function {
+            eprintln!(
+                "The interior mask {} was built for {:?}, not {:?}",
+                fname,
+                mask.params().function,
+                function
+            );
+            std::process::exit(2);
+        }
+        if mask.params().max_iterations < conf.max_iterations {
+            eprintln!(
+                "Warning: the interior mask {} was built with only {} iterations, so it may \
+                 skip some orbits which escape",
+                fname,
+                mask.params().max_iterations
+            );
+        }
+        mask
+    } else if build || save_file.is_some() {
+        if size == 0 {
+            eprintln!("The interior mask size must be at least 1");
+            std::process::exit(2);
+        }
+        let params = MaskParams {
+            function,
+            max_iterations: conf.max_iterations,
+            re_min: conf.region.startx,
+            re_max: conf.region.stopx,
+            im_min: conf.region.starty,
+            im_max: conf.region.stopy,
+        };
+        InteriorMask::build(params, size, size, thread_count)
+    } else {
+        return None;
+    };
+    eprintln!(
+        "Using a {}x{} interior mask covering {:.1}% of its region",
+        mask.width(),
+        mask.height(),
+        mask.coverage() * 100.0
+    );
+    if let Some(fname) = save_file {
+        if let Err(e) = save_mask(&mask, fname.clone()) {
+            eprintln!("Couldn't save the interior mask {}: {}", fname, e);
+            std::process::exit(2);
+        }
+    }
+    Some(Arc::new(mask))
+}
+
+// Makes the sampler for `unit`, which only depends on the seed and the unit.
+fn new_sampler(seed: u64, unit: u64, conf: &SearchConf) -> Box<dyn Sampler> {
+    let mut rng = unit_rng(seed, unit);
+    match conf.explore {
+        Some(explore) => {
+            let explore_rng: ChaChaRng = rng.gen();
+            Box::new(Explore::new(
+                base_sampler(rng, seed, unit, conf),
+                explore_rng,
+                explore,
+                conf.region,
+                conf.julia.is_some(),
+            ))
+        }
+        None => base_sampler(rng, seed, unit, conf),
+    }
+}
+
+// Makes the sampler chosen with --sampler.
+fn base_sampler(mut rng: ChaChaRng, seed: u64, unit: u64, conf: &SearchConf) -> Box<dyn Sampler> {
+    match conf.sampler {
+        SamplerKind::Uniform => Box::new(Uniform::new(rng, conf.region)),
+        SamplerKind::Metropolis => Box::new(Metropolis::new(
+            rng,
+            conf.region,
+            conf.min_iterations,
+            conf.large_step_prob,
+        )),
+        SamplerKind::Importance => Box::new(Importance::new(
+            rng,
+            conf.importance
+                .clone()
+                .expect("the importance map is built before searching"),
+        )),
+        SamplerKind::Halton => Box::new(Halton::new(&mut rng, conf.region, unit)),
+        SamplerKind::Sobol => Box::new(Sobol::new(rng, conf.region, seed, unit)),
+        SamplerKind::Stratified => {
+            Box::new(Stratified::new(rng, conf.region, conf.strata, seed, unit))
+        }
+        SamplerKind::Boundary => Box::new(Boundary::new(
+            rng,
+            conf.boundary
+                .clone()
+                .expect("the boundary cells are found before searching"),
+        )),
+        SamplerKind::Landmarks => Box::new(Landmarks::new(
+            rng,
+            conf.landmarks
+                .clone()
+                .expect("the landmarks are found before searching"),
+        )),
+    }
+}
+
+// Searches until `trajectory_count` trajectories have been found, returning them in the order
+// they were found along with what happened to the candidates tried. With quotas, trajectories
+// for buckets which are already full still count towards `trajectory_count` but are left out.
+// The search gives up early, returning what it has found, once `stop` is set.
+fn search_unit<F: IterationFunction>(
+    sampler: &mut dyn Sampler,
+    function: &F,
+    trajectory_count: usize,
+    conf: &SearchConf,
+    ticker: &Ticker,
+    stop: &AtomicBool,
+) -> (Vec<Trajectory>, SearchStats) {
+    let mut trajectories = Vec::with_capacity(trajectory_count);
+    let mut found = 0;
+    let mut stats = SearchStats::default();
+    let threshold = distance_threshold(conf.min_iterations);
+    // A trajectory the sampler is holding onto gets handed over when the search ends, so it
+    // already counts as found.
+    while found + usize::from(sampler.holding()) < trajectory_count {
+        if stop.load(Ordering::Relaxed) {
+            break;
+        }
+        let cn = sampler.propose();
+        let level = sampler.level();
+        let explored = sampler.explored();
+        stats.candidates += 1;
+        if explored {
+            stats.explored_candidates += 1;
+        }
+        if let Some(level) = level {
+            count_at(&mut stats.level_candidates, level);
+        }
+        // Points known to be interior would only use up every iteration of the estimate.
+        let too_far = conf.distance_filter
+            && !function.known_interior(cn)
+            && function
+                .distance_estimate(cn, conf.distance_iterations)
+                .is_some_and(|distance| distance > threshold);
+        let outcome = if too_far {
+            stats.distance_rejected += 1;
+            None
+        } else {
+            iterate_candidate(function, cn, conf, &mut stats)
+        };
+        if let Some(trajectory) = sampler.observe(outcome) {
+            if let Some(level) = level {
+                count_at(&mut stats.level_accepted, level);
             }
-            z = z * z + cn;
-            if z.norm() > 2.0 {
-                escaped = true;
+            if explored {
+                stats.explored_accepted += 1;
             }
-            // Check if we've encountered this point before (useful for avoiding cyclical
-            // but never ending z's). This bit of math is a fancy way of checking if
-            // itercount is a power of 2. This algorithm is called "Brent's Algorithm" and
-            // I originally found it here: https://softologyblog.wordpress.com/2011/06/26/buddhabrot-fractals/
-            if itercount & (itercount - 1) == 0 {
-                let k = format!("{:?}", z);
-                if periods.contains_key(&k) {
-                    break;
-                }
-                periods.insert(k, itercount);
+            found += 1;
+            keep(trajectory, &mut trajectories, conf, ticker);
+        }
+    }
+    if let Some(trajectory) = sampler.finish() {
+        keep(trajectory, &mut trajectories, conf, ticker);
+    }
+    stats.duplicates = sampler.duplicates();
+    (trajectories, stats)
+}
+
+// Keeps `trajectory` unless its quota bucket is already full. Only trajectories which are kept
+// count as progress, so that a search whose buckets are all full but one still stalls when it
+// stops finding anything for that one.
+fn keep(
+    trajectory: Trajectory,
+    trajectories: &mut Vec<Trajectory>,
+    conf: &SearchConf,
+    ticker: &Ticker,
+) {
+    if !bucket_full(&trajectory, conf) {
+        ticker.tick();
+        trajectories.push(trajectory);
+    }
+}
+
+// Whether the quota bucket `trajectory` falls into is already full, so it can be left out.
+fn bucket_full(trajectory: &Trajectory, conf: &SearchConf) -> bool {
+    conf.quotas
+        .as_ref()
+        .is_some_and(|q| q.is_full(q.bucket(trajectory.length)))
+}
+
+// Iterates the candidate `cn`, returning its trajectory if it's a kind of orbit being collected
+// and, when a viewport is being targeted, landed inside the viewport at least `min_view_hits`
+// times. Escaping orbits must also have lasted at least `min_iterations` iterations, while
+// bounded orbits are given a length of `max_iterations`.
+fn iterate_candidate<F: IterationFunction>(
+    function: &F,
+    candidate: Complex,
+    conf: &SearchConf,
+    stats: &mut SearchStats,
+) -> Option<Trajectory> {
+    let (z, cn) = match conf.julia {
+        Some(c) => (candidate, c),
+        None => (Complex::new(0.0, 0.0), candidate),
+    };
+    let mut trajectory: Trajectory = Trajectory::new(cn);
+    trajectory.init_z = z;
+    trajectory.formula = conf.formula;
+    trajectory.exponent = conf.exponent;
+    let mut view_hits = 0;
+    let count_hits = |z: Complex, view_hits: &mut i64| {
+        if conf.min_view_hits > 0 && conf.viewport.contains(z) {
+            *view_hits += 1;
+        }
+    };
+    // Points known to loop forever don't need iterating to find out that they're bounded. The
+    // shortcut only knows about orbits starting from 0, so it doesn't apply to Julia sets.
+    let escaped = if conf.julia.is_none() && function.known_interior(cn) {
+        false
+    } else {
+        let mut orbit = cn
+            .orbit()
+            .with_function(function)
+            .starting_from(z)
+            .max_iterations(conf.max_iterations)
+            .cycle_epsilon(conf.cycle_epsilon);
+        for z in orbit.by_ref() {
+            count_hits(z, &mut view_hits);
+        }
+        trajectory.length = orbit.iterations();
+        trajectory.period = orbit.period();
+        stats.iterations += orbit.iterations() as u64;
+        orbit.escaped()
+    };
+    if escaped {
+        if !conf.orbits.includes(OrbitKind::Escaping) || trajectory.length < conf.min_iterations {
+            return None;
+        }
+    } else {
+        if !conf.orbits.includes(OrbitKind::Bounded) {
+            return None;
+        }
+        trajectory.kind = OrbitKind::Bounded;
+        trajectory.length = conf.max_iterations;
+        // The orbit above stopped as soon as it found a cycle, but a bounded orbit gets plotted
+        // for its whole length.
+        if conf.min_view_hits > 0 {
+            view_hits = 0;
+            for z in replay(function, &trajectory) {
+                count_hits(z, &mut view_hits);
             }
         }
-        if escaped && !(trajectory.length < min_iterations) {
-            match sender.send(trajectory) {
-                Ok(_) => (),
-                Err(_) => break,
+    }
+    if conf.min_view_hits > 0 {
+        if view_hits < conf.min_view_hits {
+            return None;
+        }
+        trajectory.view_hits = Some(view_hits);
+    }
+    Some(trajectory)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    // Searches units 0 to `units` on `thread_count` threads, which claim the next unit whenever
+    // they're free just as the threads of coordinate_search do, and returns the starting points of
+    // what each unit found, in unit order.
+    fn search_units(conf: &SearchConf, units: usize, thread_count: usize) -> Vec<Vec<(f64, f64)>> {
+        let function = FormulaMap::new(conf.formula, conf.exponent);
+        let next_unit = AtomicUsize::new(0);
+        let found = Mutex::new(BTreeMap::new());
+        let watchdog = Watchdog::start("trajectories found", None);
+        let stop = AtomicBool::new(false);
+        thread::scope(|scope| {
+            for _ in 0..thread_count {
+                scope.spawn(|| loop {
+                    let unit = next_unit.fetch_add(1, Ordering::Relaxed);
+                    if unit >= units {
+                        break;
+                    }
+                    let mut sampler = new_sampler(7, unit as u64, conf);
+                    let ticker = watchdog.ticker();
+                    let (trajectories, _) =
+                        search_unit(sampler.as_mut(), &function, 4, conf, &ticker, &stop);
+                    let points = trajectories
+                        .iter()
+                        .map(|t| (t.init_c.re, t.init_c.im))
+                        .collect();
+                    found.lock().unwrap().insert(unit, points);
+                });
             }
-            valid_trajectory_count += 1;
+        });
+        found.into_inner().unwrap().into_values().collect()
+    }
+
+    #[test]
+    fn unit_order_stops_prefer_filled_quotas() {
+        let quotas = Arc::new(Quotas::new(10, 20, 1, 1));
+        let conf = SearchConf {
+            quotas: Some(quotas.clone()),
+            quota_budget: 100,
+            iteration_budget: Some(1000),
+            ..SearchConf::default()
+        };
+        let mut stats = SearchStats::default();
+        assert_eq!(unit_order_stop(&stats, &conf), None);
+        stats.iterations = 1000;
+        assert_eq!(
+            unit_order_stop(&stats, &conf),
+            Some(StopReason::IterationBudget(1000))
+        );
+        stats.candidates = 100;
+        assert_eq!(
+            unit_order_stop(&stats, &conf),
+            Some(StopReason::CandidateBudget(100))
+        );
+        quotas.take(15);
+        assert_eq!(
+            unit_order_stop(&stats, &conf),
+            Some(StopReason::QuotasFilled)
+        );
+    }
+
+    #[test]
+    fn quasi_random_searches_do_not_depend_on_threads() {
+        for &sampler in &[
+            SamplerKind::Halton,
+            SamplerKind::Sobol,
+            SamplerKind::Stratified,
+        ] {
+            let conf = SearchConf {
+                sampler,
+                min_iterations: 16,
+                max_iterations: 256,
+                strata: 8,
+                ..SearchConf::default()
+            };
+            let alone = search_units(&conf, 12, 1);
+            assert_eq!(alone, search_units(&conf, 12, 3));
+            // And the units split the sequence between them rather than repeating each other.
+            let mut points: Vec<_> = alone.iter().flatten().collect();
+            let count = points.len();
+            points.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            points.dedup();
+            assert_eq!(points.len(), count);
         }
     }
 }
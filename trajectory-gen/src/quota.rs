@@ -0,0 +1,136 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Quotas split the accepted orbit lengths into log-spaced buckets and cap how many trajectories
+/// each bucket takes, so that a run isn't swamped by the orbits lasting just over the minimum.
+///
+/// The bucket counts only ever cover a prefix of the units of work, in unit order, so a bucket
+/// seen full here is full for every unit still being searched, and threads may drop trajectories
+/// for it without changing what the run writes out.
+pub struct Quotas {
+    // The shortest length in each bucket, followed by one past the longest length of the last.
+    edges: Vec<i64>,
+    target: usize,
+    counts: Vec<AtomicUsize>,
+}
+
+impl Quotas {
+    /// Splits orbit lengths from `min_length` to `max_length` into `buckets` log-spaced buckets
+    /// taking `target` trajectories each. Buckets which would be narrower than a single length
+    /// are merged into their neighbours.
+    pub fn new(min_length: i64, max_length: i64, buckets: usize, target: usize) -> Quotas {
+        let low = min_length.max(1) as f64;
+        let high = (max_length + 1) as f64;
+        let mut edges = vec![min_length];
+        for i in 1..buckets {
+            let edge = (low * (high / low).powf(i as f64 / buckets as f64)).round() as i64;
+            if edge > *edges.last().unwrap() && edge <= max_length {
+                edges.push(edge);
+            }
+        }
+        edges.push(max_length + 1);
+        let counts = (1..edges.len()).map(|_| AtomicUsize::new(0)).collect();
+        Quotas {
+            edges,
+            target,
+            counts,
+        }
+    }
+
+    pub fn bucket_count(&self) -> usize {
+        self.counts.len()
+    }
+
+    /// The shortest and longest orbit lengths in `bucket`.
+    pub fn lengths(&self, bucket: usize) -> (i64, i64) {
+        (self.edges[bucket], self.edges[bucket + 1] - 1)
+    }
+
+    pub fn target(&self) -> usize {
+        self.target
+    }
+
+    /// The bucket an orbit of `length` iterations falls into.
+    pub fn bucket(&self, length: i64) -> usize {
+        let after = self.edges[1..].partition_point(|&edge| edge <= length);
+        after.min(self.bucket_count() - 1)
+    }
+
+    /// The number of trajectories taken into `bucket` so far.
+    pub fn count(&self, bucket: usize) -> usize {
+        self.counts[bucket].load(Ordering::Relaxed)
+    }
+
+    pub fn is_full(&self, bucket: usize) -> bool {
+        self.count(bucket) >= self.target
+    }
+
+    /// Takes an orbit of `length` iterations if its bucket has room, returning its bucket if it
+    /// was taken. Only the thread writing out trajectories in unit order may take them.
+    pub fn take(&self, length: i64) -> Option<usize> {
+        let bucket = self.bucket(length);
+        if self.is_full(bucket) {
+            return None;
+        }
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+        Some(bucket)
+    }
+
+    pub fn all_full(&self) -> bool {
+        (0..self.bucket_count()).all(|bucket| self.is_full(bucket))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn edges_cover_every_length_once() {
+        let quotas = Quotas::new(50, 50, 4, 1);
+        assert_eq!(quotas.bucket_count(), 1);
+        assert_eq!(quotas.lengths(0), (50, 50));
+
+        // More buckets than lengths: the buckets which would be empty are merged away.
+        let quotas = Quotas::new(10, 13, 10, 1);
+        assert_eq!(quotas.bucket_count(), 4);
+        for bucket in 0..4 {
+            let length = 10 + bucket as i64;
+            assert_eq!(quotas.lengths(bucket), (length, length));
+        }
+
+        let quotas = Quotas::new(64, 1024, 4, 1);
+        assert_eq!(quotas.lengths(0).0, 64);
+        assert_eq!(quotas.lengths(quotas.bucket_count() - 1).1, 1024);
+        for bucket in 1..quotas.bucket_count() {
+            assert_eq!(quotas.lengths(bucket).0, quotas.lengths(bucket - 1).1 + 1);
+        }
+    }
+
+    #[test]
+    fn lengths_out_of_range_go_in_the_end_buckets() {
+        let quotas = Quotas::new(64, 1024, 4, 1);
+        let last = quotas.bucket_count() - 1;
+        assert_eq!(quotas.bucket(0), 0);
+        assert_eq!(quotas.bucket(64), 0);
+        assert_eq!(quotas.bucket(1024), last);
+        assert_eq!(quotas.bucket(5000), last);
+        let (shortest, longest) = quotas.lengths(1);
+        assert_eq!(quotas.bucket(shortest), 1);
+        assert_eq!(quotas.bucket(longest), 1);
+    }
+
+    #[test]
+    fn take_stops_at_the_target() {
+        let quotas = Quotas::new(1, 100, 2, 2);
+        let (_, longest) = quotas.lengths(0);
+        assert_eq!(quotas.take(1), Some(0));
+        assert_eq!(quotas.take(longest), Some(0));
+        assert!(quotas.is_full(0));
+        assert_eq!(quotas.take(1), None);
+        assert_eq!(quotas.count(0), 2);
+        assert!(!quotas.all_full());
+        assert_eq!(quotas.take(100), Some(1));
+        assert_eq!(quotas.take(100), Some(1));
+        assert!(quotas.all_full());
+    }
+}
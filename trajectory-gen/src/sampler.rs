@@ -0,0 +1,873 @@
+use std::collections::{HashMap, VecDeque};
+use std::f64::consts;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use buddhabrot::buddha::{unit_rng, Complex, Trajectory};
+use buddhabrot::importance::ImportanceMap;
+use rand::Rng;
+
+use boundary::BoundaryCells;
+use discs::LandmarkDiscs;
+
+/// The rectangle of the complex plane which candidate `c` values are drawn from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Region {
+    pub startx: f64,
+    pub stopx: f64,
+    pub starty: f64,
+    pub stopy: f64,
+}
+
+impl Default for Region {
+    // centerx : -0.75
+    // centery : 0
+    // x span: [-2.5, 1.0]
+    // y span: [-1.0, 1.0]
+    fn default() -> Region {
+        Region {
+            startx: -2.5,
+            stopx: 1.0,
+            starty: -1.0,
+            stopy: 1.0,
+        }
+    }
+}
+
+impl Region {
+    /// The region starting `z` values are drawn from for Julia sets by default. Every orbit
+    /// starting outside of it escapes right away, as long as `c` is within the same bounds.
+    pub fn julia() -> Region {
+        Region {
+            startx: -2.0,
+            stopx: 2.0,
+            starty: -2.0,
+            stopy: 2.0,
+        }
+    }
+    pub fn xspan(&self) -> f64 {
+        self.stopx - self.startx
+    }
+    pub fn yspan(&self) -> f64 {
+        self.stopy - self.starty
+    }
+    pub fn contains(&self, c: Complex) -> bool {
+        c.re >= self.startx && c.re < self.stopx && c.im >= self.starty && c.im < self.stopy
+    }
+    /// Returns the point at (`u`, `v`) within this region, where (0, 0) is its smallest corner
+    /// and (1, 1) its largest.
+    pub fn at(&self, u: f64, v: f64) -> Complex {
+        Complex::new(
+            self.startx + u * self.xspan(),
+            self.starty + v * self.yspan(),
+        )
+    }
+    /// Returns a point chosen uniformly at random from within this region.
+    pub fn sample<R: Rng>(&self, rng: &mut R) -> Complex {
+        let u = rng.gen::<f64>();
+        self.at(u, rng.gen::<f64>())
+    }
+}
+
+/// The sampling strategies which may be selected from the command line.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SamplerKind {
+    Uniform,
+    Metropolis,
+    Importance,
+    Halton,
+    Sobol,
+    Stratified,
+    Boundary,
+    Landmarks,
+}
+
+impl FromStr for SamplerKind {
+    type Err = String;
+    fn from_str(s: &str) -> Result<SamplerKind, String> {
+        match s {
+            "uniform" => Ok(SamplerKind::Uniform),
+            "metropolis" | "mh" => Ok(SamplerKind::Metropolis),
+            "importance" => Ok(SamplerKind::Importance),
+            "halton" => Ok(SamplerKind::Halton),
+            "sobol" => Ok(SamplerKind::Sobol),
+            "stratified" => Ok(SamplerKind::Stratified),
+            "boundary" => Ok(SamplerKind::Boundary),
+            "landmarks" => Ok(SamplerKind::Landmarks),
+            _ => Err(format!("unknown sampler '{}'", s)),
+        }
+    }
+}
+
+impl SamplerKind {
+    /// Whether the sampler weights its trajectories to make up for favoring some points over
+    /// others. Exploring around its finds would skew the sampling in a way those weights don't
+    /// account for.
+    pub fn is_weighted(self) -> bool {
+        match self {
+            SamplerKind::Metropolis
+            | SamplerKind::Importance
+            | SamplerKind::Boundary
+            | SamplerKind::Landmarks => true,
+            SamplerKind::Uniform
+            | SamplerKind::Halton
+            | SamplerKind::Sobol
+            | SamplerKind::Stratified => false,
+        }
+    }
+}
+
+/// A Sampler decides which points get iterated: `c` values, or starting `z` values when searching
+/// a Julia set. The search loop asks the sampler for a candidate with `propose`, iterates that
+/// candidate, then hands the outcome back with `observe` (`Some` if the candidate produced an
+/// acceptable trajectory). `observe` returns the trajectory
+/// which should be emitted next, if there is one, with its importance weight filled in.
+pub trait Sampler {
+    fn propose(&mut self) -> Complex;
+    fn observe(&mut self, outcome: Option<Trajectory>) -> Option<Trajectory>;
+    /// The refinement level the last candidate proposed came from, for samplers which draw
+    /// from several levels.
+    fn level(&self) -> Option<usize> {
+        None
+    }
+    /// Whether the last candidate proposed was a perturbation of an earlier find rather than a
+    /// fresh sample.
+    fn explored(&self) -> bool {
+        false
+    }
+    /// The number of trajectories dropped so far for being near-identical to ones already
+    /// emitted.
+    fn duplicates(&self) -> u64 {
+        0
+    }
+    /// Whether the sampler is holding onto a trajectory it hasn't emitted yet, which `finish`
+    /// would hand over.
+    fn holding(&self) -> bool {
+        false
+    }
+    /// Ends the search, emitting the trajectory the sampler was holding onto, if any.
+    fn finish(&mut self) -> Option<Trajectory> {
+        None
+    }
+}
+
+/// Plain rejection sampling: every candidate is drawn uniformly from the region, and every
+/// acceptable trajectory is emitted immediately with a weight of 1.0.
+pub struct Uniform<R: Rng> {
+    rng: R,
+    region: Region,
+}
+
+impl<R: Rng> Uniform<R> {
+    pub fn new(rng: R, region: Region) -> Uniform<R> {
+        Uniform { rng, region }
+    }
+}
+
+impl<R: Rng> Sampler for Uniform<R> {
+    fn propose(&mut self) -> Complex {
+        self.region.sample(&mut self.rng)
+    }
+    fn observe(&mut self, outcome: Option<Trajectory>) -> Option<Trajectory> {
+        outcome
+    }
+}
+
+/// Metropolis-Hastings sampling over the region, in the style popularized for Buddhabrots by
+/// Alexander Boswell.
+///
+/// Once an acceptable trajectory has been found by uniform search, each following candidate is a
+/// mutation of the point which produced it: usually a small jump a short distance away,
+/// occasionally a large jump to a brand new uniformly chosen point. Both mutations are symmetric,
+/// so a candidate replaces the current state with probability `min(1, f(candidate) / f(current))`,
+/// where `f` is the length of the trajectory relative to the minimum required length, or the number
+/// of times it landed in the target viewport if one is being targeted (and 0 for unacceptable
+/// candidates).
+///
+/// The chain spends time at each state proportional to `f`, so a state is emitted once the chain
+/// moves off of it, or once the chain ends, with weight `multiplicity / f` where `multiplicity`
+/// is how many steps the chain stayed there. Weights are relative: they are only comparable
+/// between trajectories produced by the same run.
+///
+/// Each sampler runs a single chain, so every unit of work is one chain.
+pub struct Metropolis<R: Rng> {
+    rng: R,
+    region: Region,
+    min_iterations: i64,
+    large_step_prob: f64,
+    small_step_min: f64,
+    small_step_max: f64,
+    // The last point proposed, which is what the next outcome observed came from.
+    proposed: Complex,
+    // The state of the chain: its trajectory, the point which produced that trajectory, and how
+    // many steps the chain has stayed at it.
+    current: Option<(Trajectory, Complex, u64)>,
+}
+
+impl<R: Rng> Metropolis<R> {
+    pub fn new(rng: R, region: Region, min_iterations: i64, large_step_prob: f64) -> Metropolis<R> {
+        let span = region.xspan().min(region.yspan());
+        Metropolis {
+            rng,
+            region,
+            min_iterations,
+            large_step_prob,
+            small_step_min: span * 0.0001,
+            small_step_max: span * 0.1,
+            proposed: Complex::default(),
+            current: None,
+        }
+    }
+
+    fn contribution(&self, trajectory: &Trajectory) -> f64 {
+        match trajectory.view_hits {
+            Some(hits) => hits as f64,
+            None => trajectory.length as f64 / self.min_iterations.max(1) as f64,
+        }
+    }
+
+    // Emits a state the chain stayed at for `multiplicity` steps.
+    fn emit(&self, mut trajectory: Trajectory, multiplicity: u64) -> Trajectory {
+        trajectory.weight = multiplicity as f64 / self.contribution(&trajectory);
+        trajectory
+    }
+
+    /// Moves `c` in a uniformly random direction by a distance distributed log-uniformly between
+    /// `small_step_min` and `small_step_max`, which keeps most jumps tiny while still allowing
+    /// the chain to wander along the boundary of the set.
+    fn small_step(&mut self, c: Complex) -> Complex {
+        let phi = self.rng.gen::<f64>() * 2.0 * consts::PI;
+        let r = self.small_step_max
+            * (-(self.small_step_max / self.small_step_min).ln() * self.rng.gen::<f64>()).exp();
+        Complex::new(c.re + r * phi.cos(), c.im + r * phi.sin())
+    }
+}
+
+impl<R: Rng> Sampler for Metropolis<R> {
+    fn propose(&mut self) -> Complex {
+        self.proposed = match self.current {
+            Some((_, point, _)) if self.rng.gen::<f64>() >= self.large_step_prob => {
+                self.small_step(point)
+            }
+            _ => self.region.sample(&mut self.rng),
+        };
+        self.proposed
+    }
+
+    fn observe(&mut self, outcome: Option<Trajectory>) -> Option<Trajectory> {
+        let proposed = self.proposed;
+        let (current, point, multiplicity) = match self.current.take() {
+            Some(state) => state,
+            None => {
+                // Still searching for a starting state for the chain.
+                self.current = outcome.map(|t| (t, proposed, 1));
+                return None;
+            }
+        };
+        // Candidates which wandered out of the region count as unacceptable.
+        let candidate = match outcome.filter(|_| self.region.contains(proposed)) {
+            Some(t) => t,
+            None => {
+                self.current = Some((current, point, multiplicity + 1));
+                return None;
+            }
+        };
+        let acceptance = self.contribution(&candidate) / self.contribution(&current);
+        if self.rng.gen::<f64>() < acceptance {
+            self.current = Some((candidate, proposed, 1));
+            Some(self.emit(current, multiplicity))
+        } else {
+            self.current = Some((current, point, multiplicity + 1));
+            None
+        }
+    }
+
+    fn holding(&self) -> bool {
+        self.current.is_some()
+    }
+
+    fn finish(&mut self) -> Option<Trajectory> {
+        let (current, _, multiplicity) = self.current.take()?;
+        Some(self.emit(current, multiplicity))
+    }
+}
+
+/// Importance sampling from a map built by a coarse pre-pass over the region: cells are chosen in
+/// proportion to how many of the points tried in them gave orbits of the right length, and each
+/// candidate is jittered uniformly within its cell. Every trajectory is emitted immediately, with
+/// a weight of how much less likely the map made its point than uniform sampling would have,
+/// which trajectory-render adds to the pixels instead of counting the trajectory once. That
+/// gives the same expected image as uniform sampling.
+pub struct Importance<R: Rng> {
+    rng: R,
+    map: Arc<ImportanceMap>,
+    // The weight of the last point proposed.
+    weight: f64,
+}
+
+impl<R: Rng> Importance<R> {
+    pub fn new(rng: R, map: Arc<ImportanceMap>) -> Importance<R> {
+        Importance {
+            rng,
+            map,
+            weight: 1.0,
+        }
+    }
+}
+
+impl<R: Rng> Sampler for Importance<R> {
+    fn propose(&mut self) -> Complex {
+        let (point, weight) = self.map.sample(&mut self.rng);
+        self.weight = weight;
+        point
+    }
+    fn observe(&mut self, outcome: Option<Trajectory>) -> Option<Trajectory> {
+        outcome.map(|mut trajectory| {
+            trajectory.weight = self.weight;
+            trajectory
+        })
+    }
+}
+
+// The quasi-random samplers below spread their points out far more evenly than independent
+// random points, so a few thousand of them already cover the region without clumps or gaps. The
+// search is split into units of work which each get their own sampler, so each unit draws from
+// its own part of the sequence and no two units repeat each other's points, while the points of
+// all the units together are still one evenly spread set. Like every other sampler, they only
+// depend on the seed and the unit they were made for.
+
+/// The Halton sequence in bases 2 and 3. Each unit walks its own block of 2^40 indices, starting
+/// from a random index within the block so that units don't share the same first coordinates.
+pub struct Halton {
+    region: Region,
+    index: u64,
+}
+
+impl Halton {
+    pub fn new<R: Rng>(rng: &mut R, region: Region, unit: u64) -> Halton {
+        Halton {
+            region,
+            index: (unit << 40) + rng.gen::<u32>() as u64,
+        }
+    }
+}
+
+// Mirrors the digits of `i` in base `base` around the radix point.
+fn radical_inverse(mut i: u64, base: u64) -> f64 {
+    let inverse_base = 1.0 / base as f64;
+    let mut scale = inverse_base;
+    let mut result = 0.0;
+    while i > 0 {
+        result += (i % base) as f64 * scale;
+        i /= base;
+        scale *= inverse_base;
+    }
+    result
+}
+
+impl Sampler for Halton {
+    fn propose(&mut self) -> Complex {
+        let i = self.index;
+        self.index += 1;
+        self.region.at(radical_inverse(i, 2), radical_inverse(i, 3))
+    }
+    fn observe(&mut self, outcome: Option<Trajectory>) -> Option<Trajectory> {
+        outcome
+    }
+}
+
+/// The first two dimensions of the Sobol sequence with nested uniform (Owen) scrambling, using
+/// the hash based scramble from Burley's "Practical Hash-based Owen Scrambling". The units of a
+/// run share one scramble of the sequence, and each unit walks its own aligned block of 2^22 of
+/// its indices: such a block is itself evenly spread over the whole region, so each unit covers
+/// the region without drawing any point another unit draws. There are only 1024 blocks, so every
+/// further 1024 units share a new scramble, and a unit which needs more than 2^22 candidates
+/// goes around its block again, with new random bits below the 32 bits of the sequence.
+pub struct Sobol<R: Rng> {
+    rng: R,
+    region: Region,
+    // The first index of the unit's block, and how far into it the unit has got.
+    block: u32,
+    offset: u32,
+    seeds: [u32; 2],
+}
+
+// The size of each unit's block of the Sobol sequence, as a power of two.
+const SOBOL_BLOCK_BITS: u32 = 22;
+
+impl<R: Rng> Sobol<R> {
+    pub fn new(rng: R, region: Region, seed: u64, unit: u64) -> Sobol<R> {
+        let blocks = 1 << (32 - SOBOL_BLOCK_BITS);
+        let mut shared = shared_rng(seed, unit / blocks);
+        Sobol {
+            rng,
+            region,
+            block: ((unit % blocks) as u32) << SOBOL_BLOCK_BITS,
+            offset: 0,
+            seeds: [shared.gen(), shared.gen()],
+        }
+    }
+
+    // The scrambled coordinates of the unit's next point, as fractions of 2^32.
+    fn next_point(&mut self) -> (u32, u32) {
+        let i = self.block | self.offset;
+        self.offset = (self.offset + 1) & ((1 << SOBOL_BLOCK_BITS) - 1);
+        (
+            nested_uniform_scramble(sobol(i, 0), self.seeds[0]),
+            nested_uniform_scramble(sobol(i, 1), self.seeds[1]),
+        )
+    }
+}
+
+// The random numbers which the samplers of a group of units share, so that the units can split
+// one sequence between them. They come from a stream of their own, set apart from the units'
+// streams by flipping every bit of the seed.
+fn shared_rng(seed: u64, group: u64) -> impl Rng {
+    unit_rng(!seed, group)
+}
+
+// The unscrambled coordinate of point `i` of the Sobol sequence in the given dimension, as a
+// fraction of 2^32. The first dimension is the van der Corput sequence, and the second uses the
+// primitive polynomial x + 1.
+fn sobol(i: u32, dimension: usize) -> u32 {
+    if dimension == 0 {
+        return i.reverse_bits();
+    }
+    let mut direction = 1 << 31;
+    let mut result = 0;
+    let mut i = i;
+    while i > 0 {
+        if i & 1 == 1 {
+            result ^= direction;
+        }
+        direction ^= direction >> 1;
+        i >>= 1;
+    }
+    result
+}
+
+fn laine_karras_permutation(mut x: u32, seed: u32) -> u32 {
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50_b47c);
+    x ^= x.wrapping_mul(0xb82f_1e52);
+    x ^= x.wrapping_mul(0xc7af_e638);
+    x ^= x.wrapping_mul(0x8d22_f6e6);
+    x
+}
+
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    laine_karras_permutation(x.reverse_bits(), seed).reverse_bits()
+}
+
+impl<R: Rng> Sampler for Sobol<R> {
+    fn propose(&mut self) -> Complex {
+        let (x, y) = self.next_point();
+        // The sequence only has 32 bits of precision, so the bits below those are random.
+        let scale = (1u64 << 32) as f64;
+        let u = (x as f64 + self.rng.gen::<f64>()) / scale;
+        let v = (y as f64 + self.rng.gen::<f64>()) / scale;
+        self.region.at(u, v)
+    }
+    fn observe(&mut self, outcome: Option<Trajectory>) -> Option<Trajectory> {
+        outcome
+    }
+}
+
+/// Jittered stratified sampling: the region is divided into a grid of `strata` x `strata` cells,
+/// which are visited in a random order with one jittered point in each, and then visited again
+/// in a new random order once every cell has had its point. Each cell is further divided into
+/// 16 x 16 sub-cells which are shared out among the units of a run, so that every unit covers
+/// the whole region without sampling where another unit does: a unit only draws from one
+/// sub-cell of each cell, and each cell hands out its sub-cells in a differently shifted order
+/// so that a unit's sub-cells don't line up from one cell to the next. Every further 256 units
+/// share out the sub-cells again, in new orders.
+pub struct Stratified<R: Rng> {
+    rng: R,
+    region: Region,
+    strata: usize,
+    order: Vec<usize>,
+    next: usize,
+    // The unit's place in the order every cell hands out its sub-cells in, and the seed which
+    // shifts that order from cell to cell.
+    sub_cell: usize,
+    shift_seed: u32,
+}
+
+// The number of sub-cells along each side of a stratified sampler's cells.
+const SUB_STRATA: usize = 16;
+
+impl<R: Rng> Stratified<R> {
+    pub fn new(rng: R, region: Region, strata: usize, seed: u64, unit: u64) -> Stratified<R> {
+        let sub_cells = (SUB_STRATA * SUB_STRATA) as u64;
+        let order: Vec<usize> = (0..strata * strata).collect();
+        let next = order.len();
+        Stratified {
+            rng,
+            region,
+            strata,
+            order,
+            next,
+            sub_cell: (unit % sub_cells) as usize,
+            shift_seed: shared_rng(seed, unit / sub_cells).gen(),
+        }
+    }
+}
+
+impl<R: Rng> Sampler for Stratified<R> {
+    fn propose(&mut self) -> Complex {
+        if self.next == self.order.len() {
+            self.rng.shuffle(&mut self.order);
+            self.next = 0;
+        }
+        let cell = self.order[self.next];
+        self.next += 1;
+        let shift = laine_karras_permutation(cell as u32, self.shift_seed) as usize;
+        let sub_cell = (self.sub_cell + shift) % (SUB_STRATA * SUB_STRATA);
+        let column = (cell % self.strata) * SUB_STRATA + sub_cell % SUB_STRATA;
+        let row = (cell / self.strata) * SUB_STRATA + sub_cell / SUB_STRATA;
+        let n = (self.strata * SUB_STRATA) as f64;
+        let u = (column as f64 + self.rng.gen::<f64>()) / n;
+        let v = (row as f64 + self.rng.gen::<f64>()) / n;
+        self.region.at(u, v)
+    }
+    fn observe(&mut self, outcome: Option<Trajectory>) -> Option<Trajectory> {
+        outcome
+    }
+}
+
+/// Sampling concentrated on the boundary of the set: candidates are drawn from the cells of a
+/// coarse grid, and of its refinements, where escaping and bounded points meet. Every trajectory
+/// is emitted immediately, with a weight of how much less likely this made its point than
+/// uniform sampling would have. Points outside of the boundary cells are never sampled.
+pub struct Boundary<R: Rng> {
+    rng: R,
+    cells: Arc<BoundaryCells>,
+    // The level and weight of the last point proposed.
+    level: usize,
+    weight: f64,
+}
+
+impl<R: Rng> Boundary<R> {
+    pub fn new(rng: R, cells: Arc<BoundaryCells>) -> Boundary<R> {
+        Boundary {
+            rng,
+            cells,
+            level: 0,
+            weight: 1.0,
+        }
+    }
+}
+
+impl<R: Rng> Sampler for Boundary<R> {
+    fn propose(&mut self) -> Complex {
+        let (point, level, weight) = self.cells.sample(&mut self.rng);
+        self.level = level;
+        self.weight = weight;
+        point
+    }
+    fn observe(&mut self, outcome: Option<Trajectory>) -> Option<Trajectory> {
+        outcome.map(|mut trajectory| {
+            trajectory.weight = self.weight;
+            trajectory
+        })
+    }
+    fn level(&self) -> Option<usize> {
+        Some(self.level)
+    }
+}
+
+/// Sampling around landmarks, the centres of hyperbolic components and Misiurewicz points near
+/// which the longest escaping orbits are found: candidates are drawn from discs around the
+/// landmarks. Every trajectory is emitted immediately, with a weight of how much less likely this
+/// made its point than uniform sampling over the region would have. Points outside of the discs
+/// are never sampled.
+pub struct Landmarks<R: Rng> {
+    rng: R,
+    discs: Arc<LandmarkDiscs>,
+    // The weight of the last point proposed.
+    weight: f64,
+}
+
+impl<R: Rng> Landmarks<R> {
+    pub fn new(rng: R, discs: Arc<LandmarkDiscs>) -> Landmarks<R> {
+        Landmarks {
+            rng,
+            discs,
+            weight: 1.0,
+        }
+    }
+}
+
+impl<R: Rng> Sampler for Landmarks<R> {
+    fn propose(&mut self) -> Complex {
+        let (point, weight) = self.discs.sample(&mut self.rng);
+        self.weight = weight;
+        point
+    }
+    fn observe(&mut self, outcome: Option<Trajectory>) -> Option<Trajectory> {
+        outcome.map(|mut trajectory| {
+            trajectory.weight = self.weight;
+            trajectory
+        })
+    }
+}
+
+/// The settings for exploring the neighbourhoods of earlier finds.
+#[derive(Clone, Copy, Debug)]
+pub struct ExploreConf {
+    // How many perturbed candidates are drawn around each find.
+    pub samples: usize,
+    // The probability that a candidate is drawn around a find rather than by the base sampler,
+    // while there are finds left to explore.
+    pub share: f64,
+    // The distance of the first perturbation, as a fraction of the smaller span of the region.
+    pub radius: f64,
+    // How much the distance shrinks with each perturbation after the first.
+    pub shrink: f64,
+    // Trajectories starting within this distance of one already emitted by the same sampler are
+    // dropped.
+    pub dedup: f64,
+}
+
+/// Explore wraps another sampler, using every trajectory it emits as a seed for `samples`
+/// candidates scattered around the seed's point at shrinking distances, since long orbits
+/// cluster tightly together. Successful explorations become seeds in turn. Trajectories which
+/// start within `dedup` of one already emitted are dropped, so that the neighbourhood of a find
+/// doesn't keep producing copies of it. Each unit of work has a sampler of its own, so this only
+/// drops copies found within the same unit: two units may still both emit the same trajectory.
+///
+/// Explored trajectories keep a weight of 1.0: exploring favors the neighbourhoods of earlier
+/// finds in a way which can't be undone by weighting, so it's only offered on top of samplers
+/// which don't weight their trajectories either.
+pub struct Explore<R: Rng> {
+    base: Box<dyn Sampler>,
+    rng: R,
+    conf: ExploreConf,
+    // The distance of the first perturbation.
+    radius: f64,
+    // Whether the points sampled are starting `z` values of a Julia set rather than `c` values.
+    julia: bool,
+    // Seeds waiting to be explored, along with how many candidates have been drawn around them.
+    seeds: VecDeque<(Complex, usize)>,
+    exploring: bool,
+    // The starting points of the trajectories emitted so far, bucketed by `dedup` sized cells.
+    emitted: HashMap<(i64, i64), Vec<Complex>>,
+    duplicates: u64,
+}
+
+impl<R: Rng> Explore<R> {
+    pub fn new(
+        base: Box<dyn Sampler>,
+        rng: R,
+        conf: ExploreConf,
+        region: Region,
+        julia: bool,
+    ) -> Explore<R> {
+        Explore {
+            base,
+            rng,
+            conf,
+            radius: conf.radius * region.xspan().min(region.yspan()),
+            julia,
+            seeds: VecDeque::new(),
+            exploring: false,
+            emitted: HashMap::new(),
+            duplicates: 0,
+        }
+    }
+
+    fn bucket(&self, point: Complex) -> (i64, i64) {
+        (
+            (point.re / self.conf.dedup).floor() as i64,
+            (point.im / self.conf.dedup).floor() as i64,
+        )
+    }
+
+    // Records `point` as emitted, unless it's within `dedup` of a point emitted before.
+    fn record(&mut self, point: Complex) -> bool {
+        if self.conf.dedup <= 0.0 {
+            return true;
+        }
+        let (x, y) = self.bucket(point);
+        for bx in x - 1..=x + 1 {
+            for by in y - 1..=y + 1 {
+                if let Some(points) = self.emitted.get(&(bx, by)) {
+                    if points
+                        .iter()
+                        .any(|&other| (point - other).norm() < self.conf.dedup)
+                    {
+                        return false;
+                    }
+                }
+            }
+        }
+        self.emitted.entry((x, y)).or_default().push(point);
+        true
+    }
+}
+
+impl<R: Rng> Sampler for Explore<R> {
+    fn propose(&mut self) -> Complex {
+        self.exploring = !self.seeds.is_empty() && self.rng.gen::<f64>() < self.conf.share;
+        if !self.exploring {
+            return self.base.propose();
+        }
+        let (seed, drawn) = self.seeds[0];
+        if drawn + 1 >= self.conf.samples {
+            self.seeds.pop_front();
+        } else {
+            self.seeds[0].1 += 1;
+        }
+        let r = self.radius * self.conf.shrink.powi(drawn as i32);
+        let phi = self.rng.gen::<f64>() * 2.0 * consts::PI;
+        Complex::new(seed.re + r * phi.cos(), seed.im + r * phi.sin())
+    }
+    fn observe(&mut self, outcome: Option<Trajectory>) -> Option<Trajectory> {
+        let emitted = if self.exploring {
+            outcome
+        } else {
+            self.base.observe(outcome)
+        };
+        let trajectory = emitted?;
+        let point = if self.julia {
+            trajectory.init_z
+        } else {
+            trajectory.init_c
+        };
+        if !self.record(point) {
+            self.duplicates += 1;
+            return None;
+        }
+        if self.conf.samples > 0 {
+            self.seeds.push_back((point, 0));
+        }
+        Some(trajectory)
+    }
+    fn level(&self) -> Option<usize> {
+        if self.exploring {
+            None
+        } else {
+            self.base.level()
+        }
+    }
+    fn explored(&self) -> bool {
+        self.exploring
+    }
+    fn duplicates(&self) -> u64 {
+        self.duplicates
+    }
+    fn holding(&self) -> bool {
+        self.base.holding()
+    }
+    fn finish(&mut self) -> Option<Trajectory> {
+        self.base.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    // The unit square, so that sampled points are their own coordinates within the region.
+    fn unit_square() -> Region {
+        Region {
+            startx: 0.0,
+            stopx: 1.0,
+            starty: 0.0,
+            stopy: 1.0,
+        }
+    }
+
+    #[test]
+    fn sobol_units_split_one_sequence() {
+        let mut seen = HashSet::new();
+        for unit in 0..8 {
+            let mut sobol = Sobol::new(unit_rng(5, unit), unit_square(), 5, unit);
+            // Any 256 consecutive points of an aligned block put one point in each cell of a
+            // 16 x 16 grid.
+            let mut cells = HashSet::new();
+            for _ in 0..256 {
+                let c = sobol.propose();
+                cells.insert(((c.re * 16.0) as u32, (c.im * 16.0) as u32));
+            }
+            assert_eq!(cells.len(), 256);
+            let mut sobol = Sobol::new(unit_rng(5, unit), unit_square(), 5, unit);
+            for _ in 0..4096 {
+                assert!(seen.insert(sobol.next_point()));
+            }
+        }
+    }
+
+    #[test]
+    fn stratified_units_split_every_cell() {
+        let strata = 4;
+        let mut seen = HashSet::new();
+        // Enough units to go around the sub-cells of every cell more than once.
+        for unit in 0..300 {
+            let mut stratified = Stratified::new(unit_rng(5, unit), unit_square(), strata, 5, unit);
+            let fine = (strata * SUB_STRATA) as f64;
+            let mut cells = HashSet::new();
+            for _ in 0..strata * strata {
+                let c = stratified.propose();
+                let sub_cell = ((c.re * fine) as usize, (c.im * fine) as usize);
+                cells.insert((sub_cell.0 / SUB_STRATA, sub_cell.1 / SUB_STRATA));
+                // Each group of 256 units shares out the sub-cells of every cell between them.
+                assert!(seen.insert((unit / 256, sub_cell)));
+            }
+            assert_eq!(cells.len(), strata * strata);
+        }
+    }
+
+    #[test]
+    fn metropolis_chains_account_for_every_step() {
+        let min_iterations = 10;
+        let mut sampler = Metropolis::new(unit_rng(3, 0), Region::default(), min_iterations, 0.1);
+        let steps = 1000;
+        let mut emitted = vec![];
+        for _ in 0..steps {
+            let c = sampler.propose();
+            // Any length which depends only on the candidate will do.
+            let mut trajectory = Trajectory::new(c);
+            trajectory.length = min_iterations + (c.re * 1000.0).abs() as i64 % 50;
+            emitted.extend(sampler.observe(Some(trajectory)));
+        }
+        assert!(sampler.holding());
+        emitted.extend(sampler.finish());
+        assert!(!sampler.holding());
+
+        // Each state's weight is the number of steps the chain spent there, divided by its
+        // contribution, so between them the states emitted cover every step.
+        let spent: f64 = emitted
+            .iter()
+            .map(|t| t.weight * t.length as f64 / min_iterations as f64)
+            .sum();
+        assert!((spent - steps as f64).abs() < 1e-6, "{}", spent);
+    }
+
+    #[test]
+    fn explore_never_emits_a_point_twice() {
+        let conf = ExploreConf {
+            samples: 8,
+            share: 0.5,
+            radius: 0.05,
+            shrink: 0.5,
+            dedup: 0.01,
+        };
+        let base = Box::new(Uniform::new(unit_rng(7, 0), unit_square()));
+        let mut sampler = Explore::new(base, unit_rng(7, 1), conf, unit_square(), false);
+        // Every candidate makes a trajectory, so the perturbations soon shrink to well within
+        // `dedup` of their seeds and keep landing on earlier finds.
+        let mut emitted: Vec<Complex> = vec![];
+        for _ in 0..2000 {
+            let c = sampler.propose();
+            emitted.extend(sampler.observe(Some(Trajectory::new(c))).map(|t| t.init_c));
+        }
+        assert!(sampler.duplicates() > 100, "{}", sampler.duplicates());
+        for (i, &a) in emitted.iter().enumerate() {
+            for &b in &emitted[..i] {
+                assert!((a - b).norm() >= conf.dedup, "{:?} and {:?}", a, b);
+            }
+        }
+    }
+}
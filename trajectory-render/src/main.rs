@@ -7,16 +7,22 @@ extern crate serde_json;
 
 extern crate buddhabrot;
 
-use std::collections::HashMap;
-use std::io;
+use std::cmp::{max, min};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
 use std::thread;
 use std::time::Duration;
 
-use argparse::{ArgumentParser, Store, StoreTrue};
-use crossbeam_channel::{unbounded, Receiver, Sender};
+use argparse::{ArgumentParser, Collect, Store, StoreOption, StoreTrue};
+use crossbeam_channel::{bounded, Receiver, Sender};
 
-use buddhabrot::buddha::{Complex, Trajectory, Waypoint};
+use buddhabrot::buddha::{
+    replay, Complex, Formula, IterationFunction, OrbitKind, Trajectory, TrajectoryHeader,
+};
+use buddhabrot::histogram::{self, RenderParams};
 use buddhabrot::ppm;
+use buddhabrot::viewport::Viewport;
+use buddhabrot::watchdog::{Ticker, Watchdog};
 
 fn main() -> io::Result<()> {
     let mut scale_ppm_many = false;
@@ -24,6 +30,17 @@ fn main() -> io::Result<()> {
     let mut height: i64 = 1024;
     let mut width: i64 = 1024;
     let mut output_fname: String = "image.ppm".to_string();
+    let mut view = Viewport::default();
+    let mut rotate_degrees: f64 = 0.0;
+    let mut save_histogram: String = String::new();
+    let mut load_histograms: Vec<String> = vec![];
+    let mut input_fname: String = "-".to_string();
+    let mut min_length: i64 = -1;
+    let mut max_length: i64 = -1;
+    let mut queue_size: usize = 64;
+    let mut stall_seconds: f64 = 60.0;
+    let mut bounded_color: String = "gb".to_string();
+    let mut formula: Option<Formula> = None;
     {
         let mut argparse = ArgumentParser::new();
         argparse.refer(&mut scale_ppm_many).add_option(
@@ -51,43 +68,172 @@ fn main() -> io::Result<()> {
             Store,
             "Number of threads to use (default 3)",
         );
+        argparse.refer(&mut view.center.re).add_option(
+            &["--center-re"],
+            Store,
+            "Real part of the center of the rendered region (default -0.375)",
+        );
+        argparse.refer(&mut view.center.im).add_option(
+            &["--center-im"],
+            Store,
+            "Imaginary part of the center of the rendered region (default 0.0)",
+        );
+        argparse.refer(&mut view.zoom).add_option(
+            &["--zoom"],
+            Store,
+            "Magnification of the rendered region, where 1.0 is 2.25 units tall (default 1.0)",
+        );
+        argparse.refer(&mut rotate_degrees).add_option(
+            &["--rotate"],
+            Store,
+            "Degrees to rotate the rendered region counter-clockwise around its center (default 0)",
+        );
+        argparse.refer(&mut input_fname).add_option(
+            &["-i", "--input"],
+            Store,
+            "Path of the trajectory file to render, or '-' for stdin (default '-')",
+        );
+        argparse.refer(&mut min_length).add_option(
+            &["--min-length"],
+            Store,
+            "Length of the shortest trajectory, used for coloring. Taken from the header of the \
+             input if not given",
+        );
+        argparse.refer(&mut max_length).add_option(
+            &["--max-length"],
+            Store,
+            "Length of the longest trajectory, used for coloring. Taken from the header of the \
+             input if not given",
+        );
+        argparse.refer(&mut queue_size).add_option(
+            &["--queue-size"],
+            Store,
+            "Number of trajectories read ahead of the render threads (default 64)",
+        );
+        argparse.refer(&mut stall_seconds).add_option(
+            &["--stall-timeout"],
+            Store,
+            "Warn on stderr whenever this many seconds pass without rendering a trajectory \
+             (default 60, 0 disables the warning)",
+        );
+        argparse.refer(&mut bounded_color).add_option(
+            &["--bounded-color"],
+            Store,
+            "Color channels bounded (anti-Buddhabrot) orbits are plotted into, as any of the \
+             letters 'r', 'g' and 'b' (default 'gb')",
+        );
+        argparse.refer(&mut formula).add_option(
+            &["--formula"],
+            StoreOption,
+            "Only render the trajectories found with this formula: 'mandelbrot', 'burning-ship', \
+             'tricorn' or 'celtic'. Each trajectory is always replayed with the formula it was \
+             found with (default: render every trajectory)",
+        );
+        argparse.refer(&mut save_histogram).add_option(
+            &["--save-histogram"],
+            Store,
+            "Also save the raw counts of the render to this path as a lossless histogram archive",
+        );
+        argparse.refer(&mut load_histograms).add_option(
+            &["--load-histogram"],
+            Collect,
+            "Instead of rendering trajectories from stdin, load this histogram archive and write \
+             images from it. May be given multiple times to merge several archives",
+        );
         argparse.parse_args_or_exit();
     }
+    view.aspect = width as f64 / height as f64;
+    view.rotation = rotate_degrees.to_radians();
+
+    if !load_histograms.is_empty() {
+        let (imgs, params) = merge_histograms(&load_histograms)?;
+        if !save_histogram.is_empty() {
+            histogram::save_histograms(&imgs, &params, save_histogram)?;
+        }
+        write_images(&imgs, &output_fname, scale_ppm_many);
+        return Ok(());
+    }
+
     println!("Height: {}", height);
     println!("Width: {}", width);
 
-    let mut trajectories: Vec<Trajectory> = vec![];
-    let mut trajectory_count = 0;
-    let (s1, r) = unbounded();
-    loop {
-        let mut input = String::new();
-        match io::stdin().read_line(&mut input) {
-            Ok(n) => {
-                if n == 0 {
-                    break;
-                }
-                if input.trim() == "" {
-                    continue;
-                }
-                let traj: Trajectory = serde_json::from_str(input.as_str())?;
-                s1.send(traj.clone()).unwrap();
-                trajectories.push(traj.clone());
-                trajectory_count += 1;
-            }
-            Err(error) => {
-                println!("error: {}", error);
-                return Err(error);
-            }
-        };
-    }
-    let (wps, wpr) = unbounded();
+    let mut input = open_input(&input_fname)?;
+    let mut pending: Vec<String> = vec![];
+    let header = read_header(&mut input, &mut pending)?;
+    // Each of --min-length and --max-length overrides its own end of the range, and the input
+    // only needs looking at for the ends which weren't given.
+    let (min_iterations, max_iterations) = if min_length >= 0 && max_length >= 0 {
+        (min_length, max_length)
+    } else if let Some(h) = header {
+        (h.min_length, h.max_length)
+    } else if input_fname != "-" {
+        println!(
+            "No trajectory header found, scanning {} for trajectory lengths",
+            input_fname
+        );
+        scan_length_range(open_input(&input_fname)?.lines())?
+    } else {
+        // There's no way to rewind stdin, so the only option left is to hold onto all of it.
+        eprintln!(
+            "No trajectory header found on stdin and not both of --min-length and --max-length \
+             given; buffering all input to find the trajectory lengths"
+        );
+        for line in input.lines() {
+            pending.push(line?);
+        }
+        input = Box::new(io::empty());
+        scan_length_range(pending.iter().cloned().map(Ok))?
+    };
+    let min_iterations = if min_length >= 0 {
+        min_length
+    } else {
+        min_iterations
+    };
+    let max_iterations = if max_length >= 0 {
+        max_length
+    } else {
+        max_iterations
+    };
+    println!("Max length of trajectory: {}", max_iterations);
+    println!("Min length of trajectory: {}", min_iterations);
+
+    let bounded_channels = match parse_channels(&bounded_color) {
+        Ok(channels) => channels,
+        Err(e) => {
+            eprintln!("Invalid --bounded-color: {}", e);
+            std::process::exit(2);
+        }
+    };
+    let bands = ColorBands::new(min_iterations, max_iterations, bounded_channels);
+    // Zero or negative timeouts turn the warning off, but any other timeout has to fit in a
+    // Duration.
+    let stall_timeout = match Duration::try_from_secs_f64(stall_seconds) {
+        Ok(timeout) if !timeout.is_zero() => Some(timeout),
+        Ok(_) => None,
+        Err(_) if stall_seconds < 0.0 => None,
+        Err(_) => {
+            eprintln!("The stall timeout must be a finite number of seconds");
+            std::process::exit(2);
+        }
+    };
+
+    // Trajectories flow from the reader thread to the workers through a bounded queue, so that
+    // memory use stays constant no matter how large the input is. Each worker replays the orbits
+    // it receives straight into its own shard of the histograms, and finishes once the reader has
+    // finished and hung up its end of the queue.
+    let watchdog = Watchdog::start("trajectories rendered", stall_timeout);
+    let (traj_sender, traj_receiver) = bounded(queue_size);
+    let reader = thread::spawn(move || read_trajectories(pending, input, formula, traj_sender));
+    let mut workers = vec![];
     for _ in 0..thread_count {
-        let _r = r.clone();
-        let _wps = wps.clone();
-        let _ = thread::spawn(move || {
-            calculate_waypoints(_r, _wps);
-        });
+        let _r = traj_receiver.clone();
+        let ticker = watchdog.ticker();
+        let bands = bands.clone();
+        workers.push(thread::spawn(move || {
+            render_shard(_r, Trajectory::function, view, width, height, bands, ticker)
+        }));
     }
+    drop(traj_receiver);
 
     // Our vector of images, each representing a color channel, in order [r, g, b].
     let mut imgs: Vec<ppm::Img> = vec![
@@ -96,179 +242,469 @@ fn main() -> io::Result<()> {
         ppm::Img::new(width, height),
     ];
     let mut wp_added = 0;
-    println!("Trajectory count {}", trajectories.len());
-    let max_iterations = trajectories
-        .iter()
-        .fold(0, |max, x| if x.length > max { x.length } else { max });
-    let min_iterations =
-        trajectories.iter().fold(
-            std::i64::MAX,
-            |min, x| if x.length < min { x.length } else { min },
-        );
-    let timeout = Duration::from_millis(950 + (100 * max_iterations) as u64);
-    println!("Max length of trajectory: {}", max_iterations);
-    println!("Min length of trajectory: {}", min_iterations);
-    for _ in 0..trajectory_count {
-        let trajectory: Trajectory = match wpr.recv_timeout(timeout) {
-            Ok(t) => t,
-            Err(_) => {
-                break;
-            }
+    let mut trajectory_count = 0;
+    for worker in workers {
+        let shard = worker.join().expect("a render thread panicked");
+        for (img, shard_img) in imgs.iter_mut().zip(shard.imgs.iter()) {
+            img.add(shard_img);
+        }
+        wp_added += shard.waypoints;
+        trajectory_count += shard.trajectories;
+    }
+    drop(watchdog);
+    reader.join().expect("the trajectory reader panicked")?;
+    println!("Trajectory count {}", trajectory_count);
+    println!("Waypoints added: {}", wp_added);
+    if !save_histogram.is_empty() {
+        let params = RenderParams {
+            viewport: view,
+            min_iterations,
+            max_iterations,
+            trajectory_count,
         };
-        for p in &trajectory.waypoints {
-            let (px, py) = calc_pixel_pos(p.point.re, p.point.im, height, width);
-            if px == -1 {
+        histogram::save_histograms(&imgs, &params, save_histogram)?;
+    }
+    write_images(&imgs, &output_fname, scale_ppm_many);
+    Ok(())
+}
+
+fn open_input(fname: &str) -> io::Result<Box<dyn BufRead + Send>> {
+    if fname == "-" {
+        return Ok(Box::new(BufReader::new(io::stdin())));
+    }
+    Ok(Box::new(BufReader::new(File::open(fname)?)))
+}
+
+// Parses a single line of input, returning None for blank lines. A header may only be the first
+// line of the input, which read_header has already taken care of.
+fn parse_line(line: &str) -> io::Result<Option<Trajectory>> {
+    if line.trim() == "" {
+        return Ok(None);
+    }
+    Ok(Some(serde_json::from_str(line)?))
+}
+
+// Reads up to the first non-blank line of `input`, returning it as a header if it is one. If it's
+// a trajectory instead, it's pushed onto `pending` so it still gets rendered.
+fn read_header(
+    input: &mut Box<dyn BufRead + Send>,
+    pending: &mut Vec<String>,
+) -> io::Result<Option<TrajectoryHeader>> {
+    let mut line = String::new();
+    while input.read_line(&mut line)? > 0 {
+        if line.trim() == "" {
+            line.clear();
+            continue;
+        }
+        if let Ok(header) = serde_json::from_str::<TrajectoryHeader>(&line) {
+            return Ok(Some(header));
+        }
+        pending.push(line);
+        break;
+    }
+    Ok(None)
+}
+
+// Finds the shortest and longest escaping trajectory lengths within `lines`. Bounded trajectories
+// are left out, since they aren't colored by length.
+fn scan_length_range<I>(lines: I) -> io::Result<(i64, i64)>
+where
+    I: Iterator<Item = io::Result<String>>,
+{
+    let mut range = (i64::MAX, 0);
+    for line in lines {
+        if let Some(t) = parse_line(&line?)? {
+            if t.kind == OrbitKind::Bounded {
                 continue;
-            };
-
-            let final_iteration = trajectory.length;
-            let iter_span: f64 = (max_iterations - min_iterations) as f64;
-            let min_iters: f64 = min_iterations as f64;
-
-            // If we've set a sufficiently high minimum iteration number, then the
-            // distribution of discovered orbits will be much more uniform, so make the
-            // color distribution uniform. Otherwise, have it be inverse log base 10 to
-            // compensate for the large number of small orbits.
-            //
-            // values have been tweaked from above to give more blue and green with longer
-            // trajectories
-            let red_factor = if min_iterations > 100 { 0.70 } else { 0.10 };
-            let green_factor = if min_iterations > 100 { 0.20 } else { 0.01 };
-
-            let red_min = ((iter_span * red_factor) + min_iters) as i64;
-            let green_min = ((iter_span * green_factor) + min_iters) as i64;
-            let blue_max = green_min;
-            if max_iterations == min_iterations {
-                // If there's only one trajectory, make it white so it's very visible.
-                imgs[0].incr_px(px, py);
-                imgs[1].incr_px(px, py);
-                imgs[2].incr_px(px, py);
-            } else if final_iteration > red_min {
-                imgs[0].incr_px(px, py);
-            } else if final_iteration > green_min {
-                imgs[1].incr_px(px, py);
-            } else if final_iteration < blue_max {
-                imgs[2].incr_px(px, py);
             }
-            wp_added += 1;
+            range = (min(range.0, t.length), max(range.1, t.length));
         }
     }
-    println!("Waypoints added: {}", wp_added);
-    ppm::write_ppm(&imgs, output_fname.clone());
+    Ok(range)
+}
+
+// Sends every trajectory in `pending` and then `input` to the workers, returning how many were
+// sent. When `formula` is given, trajectories found with any other formula are skipped.
+fn read_trajectories(
+    pending: Vec<String>,
+    input: Box<dyn BufRead + Send>,
+    formula: Option<Formula>,
+    queue: Sender<Trajectory>,
+) -> io::Result<i64> {
+    let mut count = 0;
+    for line in pending.into_iter().map(Ok).chain(input.lines()) {
+        if let Some(t) = parse_line(&line?)? {
+            if formula.is_some_and(|f| f != t.formula) {
+                continue;
+            }
+            if queue.send(t).is_err() {
+                break;
+            }
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+// Loads each of the given histogram archives and adds them together. All of the archives must
+// have the same dimensions and have been rendered with the same viewport and band of orbit
+// lengths; the parameters of the first archive are kept, but with the trajectory counts of all
+// the archives summed up.
+fn merge_histograms(fnames: &[String]) -> io::Result<(Vec<ppm::Img>, RenderParams)> {
+    let (mut imgs, mut params) = histogram::load_histograms(fnames[0].clone())?;
+    for fname in fnames[1..].iter() {
+        let (more_imgs, more_params) = histogram::load_histograms(fname.clone())?;
+        let same_shape = more_imgs.len() == imgs.len()
+            && more_imgs
+                .iter()
+                .zip(imgs.iter())
+                .all(|(a, b)| a.width() == b.width() && a.height() == b.height());
+        if !same_shape {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} doesn't have the same dimensions as {}",
+                    fname, fnames[0]
+                ),
+            ));
+        }
+        let same_render = more_params.viewport == params.viewport
+            && more_params.min_iterations == params.min_iterations
+            && more_params.max_iterations == params.max_iterations;
+        if !same_render {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} wasn't rendered with the same viewport and iterations as {}",
+                    fname, fnames[0]
+                ),
+            ));
+        }
+        for (img, more) in imgs.iter_mut().zip(more_imgs.iter()) {
+            img.add(more);
+        }
+        params.trajectory_count += more_params.trajectory_count;
+    }
+    println!(
+        "Loaded {} histogram archive(s) holding {} trajectories",
+        fnames.len(),
+        params.trajectory_count
+    );
+    Ok((imgs, params))
+}
+
+fn write_images(imgs: &[ppm::Img], output_fname: &str, scale_ppm_many: bool) {
+    ppm::write_ppm(imgs, output_fname.to_string());
 
     if scale_ppm_many {
         println!("--scale-ppm-many provided, writing image to disk as PNG but scaled using many different algorithms");
-        ppm::rescale_ppm(&imgs, output_fname.clone());
+        ppm::rescale_ppm(imgs, output_fname.to_string());
     }
 
     let parts: Vec<&str> = output_fname.split(".").collect();
     let no_ext = &parts[0..parts.len() - 1].join(".");
-    ppm::write_scaled_png(&imgs, "scaled_".to_owned() + no_ext + ".png", |val, mx| {
-        ppm::fexp(val as f64, 0.100) / ppm::fexp(mx as f64, 0.100)
+    ppm::write_scaled_png(imgs, "scaled_".to_owned() + no_ext + ".png", |val, mx| {
+        ppm::fexp(val, 0.100) / ppm::fexp(mx, 0.100)
     });
-    Ok(())
 }
 
-// tells us if a point in the complex plane will loop forever by telling us if it's within the main
-// cardiod or within the second-order bulb. This returning false doesn't guarantee that there's a
-// finite number of loops, as this is just a quick special case to speed things up.
-fn will_loop_forever(z: Complex) -> bool {
-    let x = z.re;
-    let y = z.im;
-    let p: f64 = ((x - 0.25).powi(2) + y.powi(2)).sqrt();
-    if x < (p - (2.0 * p.powi(2)) + 0.25) {
-        return true;
+// Parses a color such as "gb" into the indexes of the channels it's made of.
+fn parse_channels(color: &str) -> Result<Vec<usize>, String> {
+    let mut channels = vec![];
+    for ch in color.chars() {
+        let channel = match ch {
+            'r' => 0,
+            'g' => 1,
+            'b' => 2,
+            _ => return Err(format!("'{}' isn't one of 'r', 'g' or 'b'", ch)),
+        };
+        if !channels.contains(&channel) {
+            channels.push(channel);
+        }
     }
-    if ((x + 1.0).powi(2) + y.powi(2)) < 0.0625 {
-        return true;
+    Ok(channels)
+}
+
+/// ColorBands decides which color channels each trajectory is plotted into. Escaping trajectories
+/// are colored by their length relative to the lengths of all the other trajectories, while
+/// bounded trajectories are all plotted into the same channels.
+#[derive(Clone)]
+struct ColorBands {
+    red_min: i64,
+    green_min: i64,
+    blue_max: i64,
+    all_same_length: bool,
+    bounded: Vec<usize>,
+}
+
+impl ColorBands {
+    fn new(min_iterations: i64, max_iterations: i64, bounded: Vec<usize>) -> ColorBands {
+        let iter_span: f64 = (max_iterations - min_iterations) as f64;
+        let min_iters: f64 = min_iterations as f64;
+
+        // If we've set a sufficiently high minimum iteration number, then the
+        // distribution of discovered orbits will be much more uniform, so make the
+        // color distribution uniform. Otherwise, have it be inverse log base 10 to
+        // compensate for the large number of small orbits.
+        //
+        // values have been tweaked from above to give more blue and green with longer
+        // trajectories
+        let red_factor = if min_iterations > 100 { 0.70 } else { 0.10 };
+        let green_factor = if min_iterations > 100 { 0.20 } else { 0.01 };
+
+        let green_min = ((iter_span * green_factor) + min_iters) as i64;
+        ColorBands {
+            red_min: ((iter_span * red_factor) + min_iters) as i64,
+            green_min,
+            blue_max: green_min,
+            all_same_length: max_iterations == min_iterations,
+            bounded,
+        }
+    }
+
+    /// The indexes of the channels a trajectory is plotted into.
+    fn channels(&self, trajectory: &Trajectory) -> &[usize] {
+        let length = trajectory.length;
+        if trajectory.kind == OrbitKind::Bounded {
+            &self.bounded
+        } else if self.all_same_length {
+            // If there's only one trajectory, make it white so it's very visible.
+            &[0, 1, 2]
+        } else if length > self.red_min {
+            &[0]
+        } else if length > self.green_min {
+            &[1]
+        } else if length < self.blue_max {
+            &[2]
+        } else {
+            &[]
+        }
     }
-    return false;
 }
-fn calculate_waypoints(receive_traj: Receiver<Trajectory>, send_waypoints: Sender<Trajectory>) {
-    // centerx : hard coded at -0.75
-    // centery : hard coded at 0
-    // x span: [-2.5, 1.0]
-    // y span: [-1.0, 1.0]
-
-    loop {
-        let old_traj: Trajectory = match receive_traj.try_recv() {
-            Ok(t) => t,
-            Err(_) => {
-                break;
-            }
-        };
-        let mut escaped = false;
-        let mut z = Complex::new(0.0, 0.0);
-        let cn = Complex::new(old_traj.init_c.re, old_traj.init_c.im);
-        let mut trajectory: Trajectory = Trajectory {
-            init_c: cn,
-            waypoints: Vec::new(),
-            length: 0,
-        };
-        if will_loop_forever(cn) {
+
+/// One render thread's share of the final image.
+struct Shard {
+    imgs: Vec<ppm::Img>,
+    trajectories: i64,
+    waypoints: i64,
+}
+
+// Replays the orbit of every trajectory received with the iteration function `function_of` gives
+// for it, adding the trajectory's weight to the pixels of this thread's shard as each point is
+// calculated instead of holding onto the points.
+fn render_shard<F, G>(
+    receive_traj: Receiver<Trajectory>,
+    function_of: G,
+    view: Viewport,
+    width: i64,
+    height: i64,
+    bands: ColorBands,
+    ticker: Ticker,
+) -> Shard
+where
+    F: IterationFunction,
+    G: Fn(&Trajectory) -> F,
+{
+    let mut shard = Shard {
+        imgs: vec![
+            ppm::Img::new(width, height),
+            ppm::Img::new(width, height),
+            ppm::Img::new(width, height),
+        ],
+        trajectories: 0,
+        waypoints: 0,
+    };
+    for trajectory in receive_traj.iter() {
+        let function = function_of(&trajectory);
+        // Only orbits starting from 0 can be ruled out from their c alone.
+        if trajectory.kind == OrbitKind::Escaping
+            && trajectory.init_z == Complex::default()
+            && function.known_interior(trajectory.init_c)
+        {
             continue;
         }
-        let mut periods = HashMap::new();
-        for itercount in 0..(old_traj.length) {
-            trajectory.length = itercount as i64;
-            if escaped {
-                break;
-            }
-            z = z * z + cn;
-            let waypoint = Waypoint {
-                // Ignore the image coordinates as they'll never be used in this program.
-                img_x: 0,
-                img_y: 0,
-                point: z.clone(),
-            };
-            trajectory.waypoints.push(waypoint);
-            if z.norm() > 2.0 {
-                escaped = true;
-            }
-            // Check if we've encountered this point before (useful for avoiding cyclical
-            // but never ending z's). This bit of math is a fancy way of checking if
-            // itercount is a power of 2. This algorithm is called "Brent's Algorithm" and
-            // I originally found it here: https://softologyblog.wordpress.com/2011/06/26/buddhabrot-fractals/
-            if itercount & (itercount - 1) == 0 {
-                let k = format!("{:?}", z);
-                if periods.contains_key(&k) {
-                    break;
+        shard.trajectories += 1;
+        let channels = bands.channels(&trajectory);
+        for z in replay(function, &trajectory) {
+            if let Some((px, py)) = view.to_pixel(z, width, height) {
+                for &c in channels {
+                    shard.imgs[c].add_px(px, py, trajectory.weight);
                 }
-                periods.insert(k, itercount);
-            }
-        }
-        match send_waypoints.send(trajectory) {
-            Ok(_) => (),
-            Err(_) => {
-                break;
+                shard.waypoints += 1;
             }
         }
+        ticker.tick();
     }
+    shard
 }
 
-fn calc_pixel_pos(x: f64, y: f64, height: i64, width: i64) -> (i64, i64) {
-    // original
-    //let (startx, stopx): (f64, f64) = (-2.25, 0.75);
-    //let (starty, stopy): (f64, f64) = (-1.5, 1.5);
-
-    // cuts off the bottom
-    //let (startx, stopx): (f64, f64) = (-1.75, 0.25);
-    //let (starty, stopy): (f64, f64) = (-1.0, 1.0);
-
-    // Shrunk by 1/4 from the left, 1/8 top & 1/8 bottom
-    // 1/4 = 0.75
-    // 1/8 = 0.375
-    // This one centers the frame SO much better!
-    let (startx, stopx): (f64, f64) = (-1.5, 0.75);
-    let (starty, stopy): (f64, f64) = (-1.125, 1.125);
-
-    let xspan = stopx - startx;
-    let yspan = stopy - starty;
-
-    let xp = (x - startx) / xspan * width as f64;
-    let yp = (y - starty) / yspan * height as f64;
-    if xp < 0.0 || xp >= (width as f64) || yp < 0.0 || yp >= (height as f64) {
-        return (-1, -1);
+#[cfg(test)]
+mod tests {
+    extern crate rand;
+
+    use self::rand::Rng;
+    use super::*;
+    use buddhabrot::buddha::{unit_rng, FormulaMap};
+    use buddhabrot::importance::{ImportanceMap, ImportanceParams};
+
+    const MIN_ITERATIONS: i64 = 20;
+    const MAX_ITERATIONS: i64 = 200;
+    const CANDIDATES: usize = 100000;
+    const SIDE: i64 = 8;
+
+    fn params() -> ImportanceParams {
+        ImportanceParams {
+            function: FormulaMap::new(Formula::Mandelbrot, 2.0),
+            julia: None,
+            min_iterations: MIN_ITERATIONS,
+            max_iterations: MAX_ITERATIONS,
+            re_min: -2.0,
+            re_max: 1.0,
+            im_min: -1.5,
+            im_max: 1.5,
+        }
+    }
+
+    // The trajectory of `c` with the given weight, if its orbit escapes within the band.
+    fn trajectory(c: Complex, weight: f64) -> Option<Trajectory> {
+        let mut orbit = c.orbit().max_iterations(MAX_ITERATIONS);
+        orbit.by_ref().count();
+        if !orbit.escaped() || orbit.iterations() < MIN_ITERATIONS {
+            return None;
+        }
+        let mut trajectory = Trajectory::new(c);
+        trajectory.length = orbit.iterations();
+        trajectory.weight = weight;
+        Some(trajectory)
+    }
+
+    // Renders `trajectories` through render_shard, returning the brightness of every pixel summed
+    // over the channels, per candidate tried.
+    fn render(trajectories: Vec<Trajectory>) -> Vec<f64> {
+        let (send_traj, receive_traj) = bounded(trajectories.len());
+        for trajectory in trajectories {
+            send_traj.send(trajectory).unwrap();
+        }
+        drop(send_traj);
+        let watchdog = Watchdog::start("Rendering", None);
+        let bands = ColorBands::new(MIN_ITERATIONS, MAX_ITERATIONS, vec![1, 2]);
+        let view = Viewport::default();
+        let shard = render_shard(
+            receive_traj,
+            Trajectory::function,
+            view,
+            SIDE,
+            SIDE,
+            bands,
+            watchdog.ticker(),
+        );
+        (0..(SIDE * SIDE) as usize)
+            .map(|i| shard.imgs.iter().map(|img| img.pixels()[i]).sum::<f64>())
+            .map(|brightness| brightness / CANDIDATES as f64)
+            .collect()
+    }
+
+    fn temp_path(name: &str) -> String {
+        let mut path = std::env::temp_dir();
+        path.push(format!(
+            "trajectory-render-test-{}-{}",
+            std::process::id(),
+            name
+        ));
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn merge_histograms_requires_matching_renders() {
+        let params = RenderParams {
+            viewport: Viewport::default(),
+            min_iterations: MIN_ITERATIONS,
+            max_iterations: MAX_ITERATIONS,
+            trajectory_count: 5,
+        };
+        let mut imgs = vec![
+            ppm::Img::new(4, 2),
+            ppm::Img::new(4, 2),
+            ppm::Img::new(4, 2),
+        ];
+        imgs[0].set_px(1, 1, 3.0);
+        let mut zoomed = params.clone();
+        zoomed.viewport.zoom = 2.0;
+        let mut longer = params.clone();
+        longer.max_iterations += 1;
+        let fnames: Vec<String> = ["a", "b", "zoomed", "longer"]
+            .iter()
+            .map(|name| temp_path(&format!("{}.hist", name)))
+            .collect();
+        for (fname, params) in fnames
+            .iter()
+            .zip([&params, &params, &zoomed, &longer].iter())
+        {
+            histogram::save_histograms(&imgs, params, fname.clone()).unwrap();
+        }
+
+        let merged = merge_histograms(&fnames[..2]);
+        let zoomed = merge_histograms(&[fnames[0].clone(), fnames[2].clone()]);
+        let longer = merge_histograms(&[fnames[0].clone(), fnames[3].clone()]);
+        for fname in fnames.iter() {
+            std::fs::remove_file(fname).unwrap();
+        }
+
+        let (merged, merged_params) = merged.unwrap();
+        assert_eq!(merged[0].get_px(1, 1), Some(6.0));
+        assert_eq!(merged_params.trajectory_count, 10);
+        assert!(zoomed.is_err());
+        assert!(longer.is_err());
+    }
+
+    // The difference between two images relative to the brightness of the first.
+    fn difference(expected: &[f64], actual: &[f64]) -> f64 {
+        let diff: f64 = expected
+            .iter()
+            .zip(actual)
+            .map(|(e, a)| (e - a).abs())
+            .sum();
+        diff / expected.iter().sum::<f64>()
+    }
+
+    #[test]
+    fn weighted_render_matches_uniform_render() {
+        let params = params();
+        let mut rng = unit_rng(1, 0);
+        let uniform: Vec<Trajectory> = (0..CANDIDATES)
+            .filter_map(|_| {
+                let re = rng.gen_range(params.re_min, params.re_max);
+                let im = rng.gen_range(params.im_min, params.im_max);
+                trajectory(Complex::new(re, im), 1.0)
+            })
+            .collect();
+
+        let map = ImportanceMap::build(params, 24, 24, 3, 1);
+        let mut rng = unit_rng(2, 0);
+        let sampled: Vec<(Complex, f64)> = (0..CANDIDATES).map(|_| map.sample(&mut rng)).collect();
+        let weighted: Vec<Trajectory> = sampled
+            .iter()
+            .filter_map(|&(c, weight)| trajectory(c, weight))
+            .collect();
+        let unweighted: Vec<Trajectory> = sampled
+            .iter()
+            .filter_map(|&(c, _)| trajectory(c, 1.0))
+            .collect();
+
+        let uniform = render(uniform);
+        let weighted_diff = difference(&uniform, &render(weighted));
+        // Without their weights, the trajectories from the cells the map favors come out far too
+        // bright.
+        let unweighted_diff = difference(&uniform, &render(unweighted));
+        assert!(
+            weighted_diff < 0.15,
+            "weighted render is off by {}",
+            weighted_diff
+        );
+        assert!(
+            unweighted_diff > 1.0,
+            "unweighted render is off by {}",
+            unweighted_diff
+        );
     }
-    return (xp as i64, yp as i64);
 }